@@ -0,0 +1,147 @@
+//! Compares searching and name lookups through `packages.json` with the binary search index
+//!
+//! Run with `cargo bench`, `NPKG_BENCH_PACKAGES` sets the number of generated packages.
+
+use npkg::{writeindex, Index};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+#[derive(Deserialize)]
+struct PackageBase {
+    packages: HashMap<String, Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+    meta: Meta,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    description: Option<String>,
+}
+
+const WORDS: [&str; 10] = [
+    "tool", "library", "editor", "git", "network", "python", "font", "terminal", "graphics",
+    "audio",
+];
+
+/// Returns the attribute of the `i`th generated package
+// `usize::is_multiple_of` needs Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+fn genattr(i: usize) -> String {
+    if i % 7 == 0 {
+        format!("python3Packages.pkg{}", i)
+    } else {
+        format!("pkg{}-{}", i, WORDS[i % WORDS.len()])
+    }
+}
+
+/// Writes a `packages.json` with `n` packages shaped like the ones in nixpkgs
+fn genpackages(n: usize) -> String {
+    let mut pkgs = vec![];
+    for i in 0..n {
+        let attr = genattr(i);
+        pkgs.push(format!(
+            "\"{}\": {{\"name\": \"{}-1.{}\", \"pname\": \"{}\", \"version\": \"1.{}\", \"meta\": {{\"description\": \"A {} {} for testing\", \"homepage\": \"https://example.org/{}\", \"available\": true, \"broken\": false}}}}",
+            attr, attr, i, attr, i, WORDS[(i / 3) % WORDS.len()], WORDS[(i / 5) % WORDS.len()], i
+        ));
+    }
+    format!("{{\"version\": 2, \"packages\": {{{}}}}}", pkgs.join(",\n"))
+}
+
+/// Runs `f` `iters` times and returns the average duration
+fn time<T>(iters: u32, mut f: impl FnMut() -> T) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iters {
+        black_box(f());
+    }
+    start.elapsed() / iters
+}
+
+fn main() {
+    let n = env::var("NPKG_BENCH_PACKAGES")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(100_000);
+    let dir = tempfile::tempdir().unwrap();
+    let json = dir.path().join("packages.json");
+    let idx = dir.path().join("packages.idx");
+    fs::write(&json, genpackages(n)).unwrap();
+    let query = vec!["git".to_string(), "editor".to_string()];
+    let name = format!("{}-1.{}", genattr(n / 2), n / 2);
+
+    let build = time(1, || writeindex(&json, &idx).unwrap());
+    println!(
+        "{} packages, packages.json {} KiB, index {} KiB, index built in {:?}",
+        n,
+        fs::metadata(&json).unwrap().len() / 1024,
+        fs::metadata(&idx).unwrap().len() / 1024,
+        build
+    );
+
+    let old = time(5, || {
+        let data: PackageBase = serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+        let mut out = data
+            .packages
+            .iter()
+            .filter(|(attr, pkg)| {
+                query.iter().all(|q| {
+                    let q = q.to_lowercase();
+                    attr.to_lowercase().contains(&q)
+                        || pkg
+                            .meta
+                            .description
+                            .as_ref()
+                            .map(|x| x.to_lowercase().contains(&q))
+                            .unwrap_or(false)
+                })
+            })
+            .map(|(attr, pkg)| (attr.to_string(), pkg.version.to_string()))
+            .collect::<Vec<_>>();
+        out.sort();
+        out
+    });
+    let new = time(5, || {
+        let index = Index::open(&idx).unwrap();
+        index
+            .search(&query)
+            .iter()
+            .map(|x| (x.attr.to_string(), x.version.to_string()))
+            .collect::<Vec<_>>()
+    });
+    println!(
+        "search       packages.json {:>12?}  index {:>12?}",
+        old, new
+    );
+    let ranked = time(5, || Index::open(&idx).unwrap().rank(&query));
+    println!(
+        "ranked search                            index {:>12?}",
+        ranked
+    );
+
+    let old = time(5, || {
+        let data: PackageBase = serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+        let names = data
+            .packages
+            .into_iter()
+            .map(|(attr, pkg)| (pkg.name, attr))
+            .collect::<HashMap<_, _>>();
+        names.get(&name).cloned()
+    });
+    let new = time(5, || {
+        let index = Index::open(&idx).unwrap();
+        index.byname(&name).map(|x| x.attr.to_string())
+    });
+    println!(
+        "name lookup  packages.json {:>12?}  index {:>12?}",
+        old, new
+    );
+}
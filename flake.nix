@@ -24,6 +24,7 @@
             buildInputs = with pkgs; [
               openssl
               pkgconfig
+              xz
             ];
           };
 
@@ -41,7 +42,7 @@
           devShells = {
             default = pkgs.mkShell {
               nativeBuildInputs = 
-                with pkgs; [ rustc cargo openssl pkgconfig ] ;
+                with pkgs; [ rustc cargo openssl pkgconfig xz ] ;
             };
           };
         }
@@ -31,10 +31,50 @@ in
         example = literalExpression ''/home/user/nix/flake.nix'';
         description = ''Where npkg looks for flake.nix'';
       };
+      nixprofile = mkOption {
+        type = types.bool;
+        default = false;
+        description = ''Whether npkg uses nix profile instead of nix-env to manage the user environment'';
+      };
+      channel = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = "nixpkgs";
+        description = ''Channel nix-env installs packages from, detected from nix-channel --list if null'';
+      };
+      systemtarget = mkOption {
+        type = with types; nullOr path;
+        default = null;
+        example = literalExpression ''/etc/nixos/packages.nix'';
+        description = ''File new system packages are added to, systemconfig if null'';
+      };
+      hometarget = mkOption {
+        type = with types; nullOr path;
+        default = null;
+        example = literalExpression ''/home/user/.config/nixpkgs/packages.nix'';
+        description = ''File new home-manager packages are added to, homeconfig if null'';
+      };
+      cache = mkOption {
+        type = types.enum [ "download" "nix-env" "nix-search" ];
+        default = "download";
+        description = ''How npkg builds its package cache: download the package list of the release, or evaluate nixpkgs locally with nix-env or nix search'';
+      };
+      packagesurl = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = "https://mirror.example.org/nixos/{release}/nixos-{version}/packages.json.br";
+        description = ''URL or local path of the package list npkg downloads, with {release} and {version} replaced by the nixpkgs release and version. releases.nixos.org if null'';
+      };
+      sources = mkOption {
+        type = with types; listOf str;
+        default = [ ];
+        example = [ "nixos-unstable" "flake:nixpkgs-unstable" "nur" ];
+        description = ''Other channels, flake inputs or nur npkg searches alongside the main nixpkgs'';
+      };
     };
   };
 
-  config = mkIf (cfg.systemconfig != "/etc/nixos/configuration.nix" || cfg.homeconfig != "${config.home.homeDirectory}/.config/nixpkgs/home.nix" || cfg.flake != null) {
+  config = mkIf (cfg.systemconfig != "/etc/nixos/configuration.nix" || cfg.homeconfig != "${config.home.homeDirectory}/.config/nixpkgs/home.nix" || cfg.flake != null || cfg.nixprofile || cfg.channel != null || cfg.systemtarget != null || cfg.hometarget != null || cfg.cache != "download" || cfg.packagesurl != null || cfg.sources != [ ]) {
     xdg.configFile."npkg/config.json".source = jsonFormat.generate "config.json" cfg;
   };
 }
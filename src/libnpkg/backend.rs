@@ -0,0 +1,624 @@
+use crate::execute::{
+    envinstall, envremove, envupdate, homeflakeswitch, homeswitch, listchannels, pkrm, pkwrite,
+    profileinstall, profileremove, profileupgrade, systemflakeswitch, systemswitch, ExecuteError,
+};
+use crate::index::Index;
+use crate::runner::CommandRunner;
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A change to a configuration file that has not been written yet
+#[derive(Debug, Clone)]
+pub struct FileEdit {
+    pub file: String,
+    /// The current contents of the file
+    pub old: String,
+    /// The contents with the change made
+    pub new: String,
+}
+
+/// A set of packages npkg can install to and remove from
+///
+/// Declarative backends like [`System`] and [`Home`] edit a configuration file, which takes
+/// effect once the edits are written and [`Backend::apply`] is called. Imperative backends like
+/// [`Env`] and [`Profile`] change the installed packages right away.
+pub trait Backend {
+    /// Names the package set in messages, e.g. `system` or `nix environment`
+    fn describe(&self) -> &str;
+
+    /// Names the package set in headings, e.g. `System` or `Nix Environment`
+    fn label(&self) -> &str;
+
+    /// Identifies the package set in machine readable output, one of `system`, `home`, `env` or
+    /// `profile`
+    fn source(&self) -> &str;
+
+    /// The main configuration file of declarative backends, `None` for imperative ones
+    fn config(&self) -> Option<&str> {
+        None
+    }
+
+    /// The file declarative backends add new packages to, the main configuration file or a file
+    /// it imports
+    fn target(&self) -> Option<&str> {
+        None
+    }
+
+    /// The flake the configuration is rebuilt from, if any
+    fn flake(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether activating the configuration needs root access
+    fn root(&self) -> bool {
+        false
+    }
+
+    /// Nix expression of the package set new packages are taken from, e.g. `(import <nixos> {})`
+    ///
+    /// `None` for configurations, which take packages from the nixpkgs they are built with.
+    fn pkgset(&self, _runner: &dyn CommandRunner) -> Option<String> {
+        None
+    }
+
+    /// Lists the attributes of the installed packages, sorted
+    fn list(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, ExecuteError>;
+
+    /// Lists the installed packages together with the configuration file declaring each, sorted
+    ///
+    /// Packages of imperative backends are not declared in a file.
+    fn files(
+        &self,
+        runner: &dyn CommandRunner,
+    ) -> Result<Vec<(String, Option<String>)>, ExecuteError> {
+        Ok(self.list(runner)?.into_iter().map(|x| (x, None)).collect())
+    }
+
+    /// Installs packages given as attributes
+    ///
+    /// Returns the edits to make to configuration files, which is empty for imperative backends.
+    fn install(
+        &self,
+        runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError>;
+
+    /// Removes installed packages given as attributes
+    ///
+    /// Returns the edits to make to configuration files, which is empty for imperative backends.
+    fn remove(
+        &self,
+        runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError>;
+
+    /// Upgrades the installed packages to the versions in their channels or flake
+    fn update(&self, runner: &dyn CommandRunner) -> Result<(), ExecuteError>;
+
+    /// Activates the configuration once its edits are written
+    ///
+    /// Does nothing for imperative backends.
+    fn apply(&self, runner: &dyn CommandRunner) -> Result<(), ExecuteError>;
+}
+
+/// Packages in the NixOS configuration, activated with `nixos-rebuild switch`
+pub struct System {
+    /// The main configuration file, e.g. `/etc/nixos/configuration.nix`
+    pub config: String,
+    /// The file new packages are added to, `config` or a file it imports
+    pub target: String,
+    /// List option to manage instead of `environment.systemPackages`
+    pub attr: Option<String>,
+    /// Flake to rebuild from, e.g. `/etc/nixos#host`
+    pub flake: Option<String>,
+}
+
+impl Backend for System {
+    fn describe(&self) -> &str {
+        "system"
+    }
+
+    fn label(&self) -> &str {
+        "System"
+    }
+
+    fn source(&self) -> &str {
+        "system"
+    }
+
+    fn config(&self) -> Option<&str> {
+        Some(&self.config)
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.target)
+    }
+
+    fn flake(&self) -> Option<&str> {
+        self.flake.as_deref()
+    }
+
+    fn root(&self) -> bool {
+        true
+    }
+
+    fn list(&self, _runner: &dyn CommandRunner) -> Result<Vec<String>, ExecuteError> {
+        cfglist(&self.config, self.query())
+    }
+
+    fn files(
+        &self,
+        _runner: &dyn CommandRunner,
+    ) -> Result<Vec<(String, Option<String>)>, ExecuteError> {
+        cfgdeclared(&self.config, self.query())
+    }
+
+    fn install(
+        &self,
+        _runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError> {
+        cfginstall(&self.target, self.query(), pkgs)
+    }
+
+    fn remove(
+        &self,
+        _runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError> {
+        cfgremove(&self.config, self.query(), pkgs)
+    }
+
+    /// Rebuilds the system, channels have to be updated first
+    fn update(&self, runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+        self.apply(runner)
+    }
+
+    fn apply(&self, runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+        match &self.flake {
+            Some(flake) => systemflakeswitch(runner, flake),
+            None => systemswitch(runner),
+        }
+    }
+}
+
+impl System {
+    fn query(&self) -> &str {
+        self.attr.as_deref().unwrap_or("environment.systemPackages")
+    }
+}
+
+/// Packages in the home-manager configuration, activated with `home-manager switch`
+pub struct Home {
+    /// The main configuration file, e.g. `~/.config/nixpkgs/home.nix`
+    pub config: String,
+    /// The file new packages are added to, `config` or a file it imports
+    pub target: String,
+    /// List option to manage instead of `home.packages`
+    pub attr: Option<String>,
+    /// Flake to switch to, e.g. `~/nix#user`
+    pub flake: Option<String>,
+}
+
+impl Backend for Home {
+    fn describe(&self) -> &str {
+        "home-manager"
+    }
+
+    fn label(&self) -> &str {
+        "Home Manager"
+    }
+
+    fn source(&self) -> &str {
+        "home"
+    }
+
+    fn config(&self) -> Option<&str> {
+        Some(&self.config)
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.target)
+    }
+
+    fn flake(&self) -> Option<&str> {
+        self.flake.as_deref()
+    }
+
+    fn list(&self, _runner: &dyn CommandRunner) -> Result<Vec<String>, ExecuteError> {
+        cfglist(&self.config, self.query())
+    }
+
+    fn files(
+        &self,
+        _runner: &dyn CommandRunner,
+    ) -> Result<Vec<(String, Option<String>)>, ExecuteError> {
+        cfgdeclared(&self.config, self.query())
+    }
+
+    fn install(
+        &self,
+        _runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError> {
+        cfginstall(&self.target, self.query(), pkgs)
+    }
+
+    fn remove(
+        &self,
+        _runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError> {
+        cfgremove(&self.config, self.query(), pkgs)
+    }
+
+    /// Switches to the configuration again, channels have to be updated first
+    fn update(&self, runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+        self.apply(runner)
+    }
+
+    fn apply(&self, runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+        match &self.flake {
+            Some(flake) => homeflakeswitch(runner, flake),
+            None => homeswitch(runner),
+        }
+    }
+}
+
+impl Home {
+    fn query(&self) -> &str {
+        self.attr.as_deref().unwrap_or("home.packages")
+    }
+}
+
+/// Packages installed with `nix-env`
+pub struct Env {
+    /// Channel packages are installed from unless given as `channel:attr`.
+    /// Picked with [`defaultchannel`] if not set.
+    pub channel: Option<String>,
+    /// Index used to translate between the package names nix-env records and attributes
+    ///
+    /// Without an index, names are used as is.
+    pub index: Option<Index>,
+}
+
+impl Backend for Env {
+    fn describe(&self) -> &str {
+        "nix environment"
+    }
+
+    fn label(&self) -> &str {
+        "Nix Environment"
+    }
+
+    fn source(&self) -> &str {
+        "env"
+    }
+
+    fn pkgset(&self, runner: &dyn CommandRunner) -> Option<String> {
+        let channel = match &self.channel {
+            Some(x) => x.to_string(),
+            None => defaultchannel(runner),
+        };
+        Some(format!("(import <{}> {{}})", channel))
+    }
+
+    fn list(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, ExecuteError> {
+        let err = || ExecuteError::ListError("nix-env".to_string());
+        let out = runner
+            .output(Command::new("nix-env").arg("-q").arg("--json"))
+            .map_err(|_| err())?;
+        let data: Value = serde_json::from_slice(&out).map_err(|_| err())?;
+        let names = data
+            .as_object()
+            .ok_or_else(err)?
+            .values()
+            .filter_map(|x| x["name"].as_str())
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut pkgs = names
+            .into_iter()
+            .map(
+                |name| match self.index.as_ref().and_then(|x| x.byname(name)) {
+                    Some(x) => x.attr.to_string(),
+                    None => name.to_string(),
+                },
+            )
+            .collect::<Vec<_>>();
+        pkgs.sort();
+        Ok(pkgs)
+    }
+
+    fn install(
+        &self,
+        runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError> {
+        let channel = match &self.channel {
+            Some(x) => x.to_string(),
+            None => defaultchannel(runner),
+        };
+        envinstall(runner, pkgs.to_vec(), &channel)?;
+        Ok(vec![])
+    }
+
+    /// nix-env removes packages by name, so attributes are looked up in the index
+    fn remove(
+        &self,
+        runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError> {
+        let names = pkgs
+            .iter()
+            .map(
+                |attr| match self.index.as_ref().and_then(|x| x.byattr(attr)) {
+                    Some(x) => x.name.to_string(),
+                    None => attr.to_string(),
+                },
+            )
+            .collect::<Vec<_>>();
+        envremove(runner, names)?;
+        Ok(vec![])
+    }
+
+    fn update(&self, runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+        envupdate(runner)
+    }
+
+    fn apply(&self, _runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+        Ok(())
+    }
+}
+
+/// Packages installed with `nix profile` from the `nixpkgs` flake in the registry
+pub struct Profile;
+
+impl Backend for Profile {
+    fn describe(&self) -> &str {
+        "nix profile"
+    }
+
+    fn label(&self) -> &str {
+        "Nix Profile"
+    }
+
+    fn source(&self) -> &str {
+        "profile"
+    }
+
+    /// Packages are installed from the `nixpkgs` flake in the registry
+    fn pkgset(&self, _runner: &dyn CommandRunner) -> Option<String> {
+        Some("(builtins.getFlake \"nixpkgs\").legacyPackages.${builtins.currentSystem}".to_string())
+    }
+
+    fn list(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, ExecuteError> {
+        let mut pkgs = profileelems(runner)?
+            .into_iter()
+            .map(|(attr, _)| attr)
+            .collect::<Vec<_>>();
+        pkgs.sort();
+        Ok(pkgs)
+    }
+
+    fn install(
+        &self,
+        runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError> {
+        profileinstall(runner, pkgs.to_vec())?;
+        Ok(vec![])
+    }
+
+    /// Every element providing one of the attributes is removed
+    fn remove(
+        &self,
+        runner: &dyn CommandRunner,
+        pkgs: &[String],
+    ) -> Result<Vec<FileEdit>, ExecuteError> {
+        let mut elems = vec![];
+        for (attr, elem) in profileelems(runner)? {
+            if pkgs.contains(&attr) && !elems.contains(&elem) {
+                elems.push(elem);
+            }
+        }
+        if !elems.is_empty() {
+            profileremove(runner, elems)?;
+        }
+        Ok(vec![])
+    }
+
+    fn update(&self, runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+        profileupgrade(runner)
+    }
+
+    fn apply(&self, _runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+        Ok(())
+    }
+}
+
+/// Picks the channel nix-env installs from when none is configured
+///
+/// Prefers `nixos`, then `nixpkgs`, then the first channel listed by `nix-channel --list`.
+/// On NixOS the `nixos` channel usually belongs to root and is not listed, so it is used as a fallback.
+pub fn defaultchannel(runner: &dyn CommandRunner) -> String {
+    let channels = listchannels(runner).unwrap_or_default();
+    for c in ["nixos", "nixpkgs"] {
+        if channels.iter().any(|x| x == c) {
+            return c.to_string();
+        }
+    }
+    match channels.first() {
+        Some(x) => x.to_string(),
+        None => "nixos".to_string(),
+    }
+}
+
+/// Returns the attribute and element name of every package in the default `nix profile`
+///
+/// Newer versions of nix identify elements by name, older versions by their index.
+pub fn profileelems(runner: &dyn CommandRunner) -> Result<Vec<(String, String)>, ExecuteError> {
+    let err = || ExecuteError::ListError("nix profile".to_string());
+    let out = runner
+        .output(Command::new("nix").arg("profile").arg("list").arg("--json"))
+        .map_err(|_| err())?;
+    let data: Value = serde_json::from_slice(&out).map_err(|_| err())?;
+
+    let elems = match &data["elements"] {
+        Value::Object(x) => x
+            .iter()
+            .map(|(name, elem)| (name.to_string(), elem))
+            .collect::<Vec<_>>(),
+        Value::Array(x) => x
+            .iter()
+            .enumerate()
+            .map(|(i, elem)| (i.to_string(), elem))
+            .collect::<Vec<_>>(),
+        _ => return Err(err()),
+    };
+
+    let mut pkgs = vec![];
+    for (name, elem) in elems {
+        // Attribute paths look like `legacyPackages.x86_64-linux.hello`
+        let attr = match elem["attrPath"].as_str() {
+            Some(x) => x.splitn(3, '.').nth(2).unwrap_or(x).to_string(),
+            None => name.to_string(),
+        };
+        pkgs.push((attr, name));
+    }
+    Ok(pkgs)
+}
+
+/// Lists the packages in the list option `query` of `file` and every file it imports
+///
+/// Returns each file together with the packages it declares. Files that do not set `query` are left out.
+pub fn cfgpkgs(file: &str, query: &str) -> Result<Vec<(String, Vec<String>)>, ExecuteError> {
+    let mut out = vec![];
+    for (i, path) in cfgfiles(file).into_iter().enumerate() {
+        let f = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(_) if i > 0 => continue,
+            Err(_) => return Err(ExecuteError::ListError(path)),
+        };
+
+        match nix_editor::read::getarrvals(&f, query) {
+            Ok(x) => out.push((path, x)),
+            // A list that is not set yet has no packages
+            Err(nix_editor::read::ReadError::NoAttr) => {}
+            // Imported files can be arbitrary nix expressions, only the main file has to be readable
+            Err(_) if i > 0 => {}
+            Err(_) => return Err(ExecuteError::ListError(path)),
+        }
+    }
+    Ok(out)
+}
+
+/// Returns `file` followed by every file it imports, recursively
+///
+/// Only imports given as paths are followed, imports of a directory read its `default.nix`.
+pub fn cfgfiles(file: &str) -> Vec<String> {
+    let mut files = vec![file.to_string()];
+    // Canonical paths of the files found so far, so each file is only read once
+    let mut seen = vec![fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file))];
+    let mut i = 0;
+    while i < files.len() {
+        let f = match fs::read_to_string(&files[i]) {
+            Ok(x) => x,
+            Err(_) => {
+                i += 1;
+                continue;
+            }
+        };
+        let dir = Path::new(&files[i])
+            .parent()
+            .map(|x| x.to_path_buf())
+            .unwrap_or_default();
+        for import in nix_editor::read::getarrvals(&f, "imports").unwrap_or_default() {
+            let import = import.trim_matches('"');
+            // Skip `<channel/path>` lookups, flake inputs and function calls
+            if !(import.starts_with('.') || import.starts_with('/')) {
+                continue;
+            }
+            let mut path = dir.join(import);
+            if path.is_dir() {
+                path = path.join("default.nix");
+            }
+            if !path.is_file() {
+                continue;
+            }
+            let path = match fs::canonicalize(&path) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            if !seen.contains(&path) {
+                files.push(path.to_string_lossy().to_string());
+                seen.push(path);
+            }
+        }
+        i += 1;
+    }
+    files
+}
+
+/// Lists the packages of `config` and the files it imports, sorted and without duplicates
+fn cfglist(config: &str, query: &str) -> Result<Vec<String>, ExecuteError> {
+    let mut pkgs = vec![];
+    for (_, filepkgs) in cfgpkgs(config, query)? {
+        for p in filepkgs {
+            if !pkgs.contains(&p) {
+                pkgs.push(p);
+            }
+        }
+    }
+    pkgs.sort();
+    Ok(pkgs)
+}
+
+/// Lists the packages of `config` and the files it imports, each with the file declaring it
+fn cfgdeclared(config: &str, query: &str) -> Result<Vec<(String, Option<String>)>, ExecuteError> {
+    let mut pkgs = vec![];
+    for (file, filepkgs) in cfgpkgs(config, query)? {
+        for p in filepkgs {
+            pkgs.push((p, Some(file.to_string())));
+        }
+    }
+    pkgs.sort();
+    Ok(pkgs)
+}
+
+fn cfginstall(target: &str, query: &str, pkgs: &[String]) -> Result<Vec<FileEdit>, ExecuteError> {
+    let old =
+        fs::read_to_string(target).map_err(|_| ExecuteError::ListError(target.to_string()))?;
+    let new = pkwrite(pkgs.to_vec(), &old, Some(query))?;
+    Ok(vec![FileEdit {
+        file: target.to_string(),
+        old,
+        new,
+    }])
+}
+
+/// Packages are removed from every file that declares them
+fn cfgremove(config: &str, query: &str, pkgs: &[String]) -> Result<Vec<FileEdit>, ExecuteError> {
+    let mut edits = vec![];
+    for (file, filepkgs) in cfgpkgs(config, query)? {
+        let rmpkgs = pkgs
+            .iter()
+            .filter(|x| filepkgs.contains(x))
+            .cloned()
+            .collect::<Vec<String>>();
+        if rmpkgs.is_empty() {
+            continue;
+        }
+        let old =
+            fs::read_to_string(&file).map_err(|_| ExecuteError::ListError(file.to_string()))?;
+        let new = pkrm(rmpkgs, &old, Some(query))?;
+        edits.push(FileEdit { file, old, new });
+    }
+    Ok(edits)
+}
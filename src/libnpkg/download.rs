@@ -0,0 +1,416 @@
+use curl::easy::{Easy, List};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+#[derive(Debug)]
+pub enum DownloadError {
+    /// Thrown when the server cannot be reached or the transfer is interrupted.
+    /// The curl error is included.
+    NetworkError(String),
+    /// Thrown when the server responds with an error status.
+    /// The URL and the HTTP status code are included.
+    HttpError(String, u32),
+    /// Thrown when reading or writing the downloaded files fails.
+    IoError(io::Error),
+    /// Thrown when the downloaded data is not valid in its compression format.
+    /// The decoder error is included.
+    DecompressError(String),
+    /// Thrown when the downloaded data does not have the expected SHA-256.
+    ChecksumError { expected: String, actual: String },
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::NetworkError(e) => write!(f, "download failed: {}", e),
+            DownloadError::HttpError(url, code) => {
+                write!(f, "server responded with status {} for {}", code, url)
+            }
+            DownloadError::IoError(e) => write!(f, "{}", e),
+            DownloadError::DecompressError(e) => write!(f, "could not decompress download: {}", e),
+            DownloadError::ChecksumError { expected, actual } => write!(
+                f,
+                "checksum mismatch, expected sha256 {} but got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for DownloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DownloadError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(e: io::Error) -> Self {
+        DownloadError::IoError(e)
+    }
+}
+
+impl From<curl::Error> for DownloadError {
+    fn from(e: curl::Error) -> Self {
+        DownloadError::NetworkError(e.to_string())
+    }
+}
+
+impl DownloadError {
+    /// Whether trying again may succeed
+    fn transient(&self) -> bool {
+        match self {
+            DownloadError::NetworkError(_) => true,
+            DownloadError::HttpError(_, code) => *code == 429 || *code >= 500,
+            _ => false,
+        }
+    }
+}
+
+/// Options for [`download`]
+pub struct DownloadOptions<'a> {
+    /// Attempts after the first one fails, each resuming where the last one stopped
+    pub retries: u32,
+    /// Wait before the first retry, doubled after every attempt
+    pub delay: Duration,
+    /// Expected SHA-256 of the compressed data, in hex
+    pub sha256: Option<String>,
+    /// Called with the number of compressed bytes downloaded so far and the total if known
+    pub progress: Option<&'a dyn Fn(u64, Option<u64>)>,
+}
+
+impl Default for DownloadOptions<'_> {
+    fn default() -> Self {
+        DownloadOptions {
+            retries: 3,
+            delay: Duration::from_secs(1),
+            sha256: None,
+            progress: None,
+        }
+    }
+}
+
+/// How a package list is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Xz,
+    Brotli,
+}
+
+impl Compression {
+    /// Detects the compression from the extension of `name`, or from the first bytes `head` of
+    /// the data if the extension is not known
+    pub fn detect(name: &str, head: &[u8]) -> Compression {
+        let name = name.split(['?', '#']).next().unwrap_or_default();
+        match name.rsplit_once('.').map(|x| x.1) {
+            Some("br") => return Compression::Brotli,
+            Some("gz") => return Compression::Gzip,
+            Some("xz") => return Compression::Xz,
+            Some("json") => return Compression::Plain,
+            _ => {}
+        }
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if head.iter().find(|x| !x.is_ascii_whitespace()) == Some(&b'{') {
+            Compression::Plain
+        } else {
+            // Brotli streams have no magic number
+            Compression::Brotli
+        }
+    }
+}
+
+/// A NixOS or nixpkgs version, as used in the release URLs on releases.nixos.org
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixosVersion {
+    /// The release the version belongs to, e.g. `22.11`, or `unstable` for pre-release versions
+    pub release: String,
+    /// The version without prefixes, codenames or custom suffixes, e.g. `22.11.1234.abcdef1` or
+    /// `23.05pre447497.3a5b2f4`
+    pub version: String,
+}
+
+impl NixosVersion {
+    /// Parses the output of `nixos-version` or the `version` of nixpkgs `lib`
+    ///
+    /// Accepts versions such as `22.11.1234.abcdef1 (Raccoon)`, `23.05pre447497.3a5b2f4`,
+    /// `nixpkgs-darwin-22.11.1234.abcdef1` and `22.11.20221201.abcdef1-dirty`. A bare `unstable`
+    /// is rejected, unstable releases are only published under their full version.
+    pub fn parse(version: &str) -> Option<NixosVersion> {
+        let version = version.trim().trim_matches('"');
+        // Drop the codename `nixos-version` prints after the version
+        let version = version.split_whitespace().next()?;
+        // Drop channel prefixes like `nixos-` or `nixpkgs-darwin-`
+        let version = &version[version.find(|c: char| c.is_ascii_digit())?..];
+
+        let mut parts = version.split('.');
+        let major = parts.next()?;
+        let minor = parts.next()?;
+        let digits = minor
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(minor.len());
+        if major.is_empty() || !major.chars().all(|c| c.is_ascii_digit()) || digits == 0 {
+            return None;
+        }
+        let mut kept = vec![major];
+        for part in std::iter::once(minor).chain(parts) {
+            // Custom suffixes like `-dirty` or `+local` end the version
+            let end = part
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(part.len());
+            if end > 0 {
+                kept.push(&part[..end]);
+            }
+            if end < part.len() {
+                break;
+            }
+        }
+        let release = if minor[digits..].starts_with("pre") {
+            "unstable".to_string()
+        } else {
+            format!("{}.{}", major, &minor[..digits])
+        };
+        Some(NixosVersion {
+            release,
+            version: kept.join("."),
+        })
+    }
+}
+
+/// Downloads the package list at `url` and decompresses it to `dest`
+///
+/// The list can be uncompressed or compressed with gzip, xz or brotli, see [`Compression::detect`].
+/// The downloaded data is kept next to `dest` with a `.part` extension until it is complete, so
+/// a failed or interrupted download resumes where it stopped. The URL is stored alongside in a
+/// `.part.url` file, and a partial download of any other URL is started over. The data is then
+/// decompressed to a temporary file that replaces `dest`, so `dest` is never left half written.
+pub fn download(url: &str, dest: &Path, opts: &DownloadOptions) -> Result<(), DownloadError> {
+    let part = sibling(dest, "part");
+    let source = sibling(&part, "url");
+    // Resuming with the data of another URL, e.g. an older nixpkgs version, would splice two files
+    if fs::read_to_string(&source).ok().as_deref() != Some(url) {
+        let _ = fs::remove_file(&part);
+        fs::write(&source, url)?;
+    }
+    let mut attempt = 0;
+    loop {
+        match fetch(url, &part, opts.progress) {
+            Ok(()) => break,
+            Err(e) if e.transient() && attempt < opts.retries => {
+                thread::sleep(opts.delay * 2u32.pow(attempt));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if let Some(expected) = &opts.sha256 {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(&part)?, &mut hasher)?;
+        let actual = hex(&hasher.finalize());
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            // Resuming a corrupt download would never succeed
+            let _ = fs::remove_file(&part);
+            let _ = fs::remove_file(&source);
+            return Err(DownloadError::ChecksumError {
+                expected: expected.trim().to_string(),
+                actual,
+            });
+        }
+    }
+
+    let res = extractas(&part, url, dest);
+    let _ = fs::remove_file(&part);
+    let _ = fs::remove_file(&source);
+    res
+}
+
+/// Decompresses the local package list `src` to `dest`, replacing `dest` only once it is complete
+pub fn extract(src: &Path, dest: &Path) -> Result<(), DownloadError> {
+    extractas(src, &src.to_string_lossy(), dest)
+}
+
+/// Decompresses `src`, which was named `name`, to `dest` through a temporary file
+fn extractas(src: &Path, name: &str, dest: &Path) -> Result<(), DownloadError> {
+    let tmp = sibling(dest, "tmp");
+    let res = decompress(src, name, &tmp);
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return res;
+    }
+    fs::rename(&tmp, dest)?;
+    Ok(())
+}
+
+/// Fetches the checksum published next to `url` as `<url>.sha256`, `None` if there is none
+pub fn checksum(url: &str) -> Result<Option<String>, DownloadError> {
+    let url = format!("{}.sha256", url);
+    let mut body = Vec::new();
+    let mut easy = Easy::new();
+    easy.url(&url)?;
+    easy.follow_location(true)?;
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|data| {
+            body.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    match easy.response_code()? {
+        200 => {}
+        404 => return Ok(None),
+        code => return Err(DownloadError::HttpError(url, code)),
+    }
+    // sha256sum output is the hash followed by the file name
+    let text = String::from_utf8_lossy(&body);
+    Ok(text
+        .split_whitespace()
+        .next()
+        .filter(|x| x.len() == 64 && x.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|x| x.to_lowercase()))
+}
+
+/// Downloads `url` to `part`, continuing after the bytes already in `part`
+fn fetch(
+    url: &str,
+    part: &Path,
+    progress: Option<&dyn Fn(u64, Option<u64>)>,
+) -> Result<(), DownloadError> {
+    let offset = fs::metadata(part).map(|x| x.len()).unwrap_or(0);
+    let file = RefCell::new(OpenOptions::new().create(true).append(true).open(part)?);
+
+    let mut easy = Easy::new();
+    easy.url(url)?;
+    easy.follow_location(true)?;
+    if offset > 0 {
+        // Set by hand, curl refuses to continue if the server ignores `resume_from`
+        let mut headers = List::new();
+        headers.append(&format!("Range: bytes={}-", offset))?;
+        easy.http_headers(headers)?;
+    }
+
+    let status = Cell::new(0);
+    let length = Cell::new(None);
+    let received = Cell::new(offset);
+    let failed = RefCell::new(None);
+    let started = Cell::new(false);
+    {
+        let mut transfer = easy.transfer();
+        transfer.header_function(|line| {
+            let line = String::from_utf8_lossy(line);
+            if line.starts_with("HTTP/") {
+                // Redirects send several responses, only the last one counts
+                status.set(
+                    line.split(' ')
+                        .nth(1)
+                        .and_then(|x| x.parse().ok())
+                        .unwrap_or(0),
+                );
+                length.set(None);
+            } else if let Some((key, value)) = line.split_once(':') {
+                if key.eq_ignore_ascii_case("content-length") {
+                    length.set(value.trim().parse::<u64>().ok());
+                }
+            }
+            true
+        })?;
+        transfer.write_function(|data| {
+            match status.get() {
+                206 => {}
+                200 => {
+                    // The server sent the whole file, the partial download is discarded
+                    if !started.get() && received.get() > 0 {
+                        if let Err(e) = file.borrow_mut().set_len(0) {
+                            *failed.borrow_mut() = Some(e);
+                            return Ok(0);
+                        }
+                        received.set(0);
+                    }
+                }
+                // Error pages are not part of the download
+                _ => return Ok(data.len()),
+            }
+            started.set(true);
+            if let Err(e) = file.borrow_mut().write_all(data) {
+                *failed.borrow_mut() = Some(e);
+                return Ok(0);
+            }
+            received.set(received.get() + data.len() as u64);
+            if let Some(f) = progress {
+                let total = length
+                    .get()
+                    .map(|x| if status.get() == 206 { x + offset } else { x });
+                f(received.get(), total);
+            }
+            Ok(data.len())
+        })?;
+        let res = transfer.perform();
+        if let Some(e) = failed.borrow_mut().take() {
+            return Err(DownloadError::IoError(e));
+        }
+        res?;
+    }
+
+    match status.get() {
+        200 | 206 => Ok(()),
+        // The partial download is not the start of the file, it may be longer than the file is
+        416 if offset > 0 => {
+            fs::remove_file(part)?;
+            fetch(url, part, progress)
+        }
+        code => Err(DownloadError::HttpError(url.to_string(), code)),
+    }
+}
+
+fn decompress(src: &Path, name: &str, dest: &Path) -> Result<(), DownloadError> {
+    let mut input = BufReader::new(File::open(src)?);
+    let compression = Compression::detect(name, input.fill_buf()?);
+    let mut reader: Box<dyn Read> = match compression {
+        Compression::Plain => Box::new(input),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(input)),
+        Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(input)),
+        Compression::Brotli => Box::new(brotli::Decompressor::new(input, 4096)),
+    };
+    let mut out = File::create(dest)?;
+    let mut buf = [0u8; 65536];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if compression == Compression::Plain => return Err(e.into()),
+            Err(e) => return Err(DownloadError::DecompressError(e.to_string())),
+        };
+        out.write_all(&buf[..n])?;
+    }
+    out.sync_all()?;
+    Ok(())
+}
+
+/// Returns `path` with `ext` appended to its file name
+fn sibling(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(ext);
+    path.with_file_name(name)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
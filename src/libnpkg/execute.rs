@@ -1,43 +1,198 @@
-use std::process::{exit, Command};
+use crate::runner::CommandRunner;
+use nix_editor::write::WriteError;
+use std::{error::Error, fmt, process::Command};
 
+#[derive(Debug)]
 pub enum ExecuteError {
     /// Thrown when running a command fails.
-    CmdError,
+    /// Details about the failed command are included.
+    CmdError(CmdFailure),
     /// Thrown when writing to a file fails.
     /// The path to the file is included.
     WriteError(String),
+    /// Thrown when a configuration file cannot be edited.
+    EditError(EditError),
+    /// Thrown when the installed packages cannot be read.
+    /// What could not be read is included.
+    ListError(String),
 }
 
-/// Installs packages using `nix-env -iA nixos.<pkg>`
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::CmdError(e) => write!(f, "{}", e),
+            ExecuteError::WriteError(path) => write!(f, "could not write to '{}'", path),
+            ExecuteError::EditError(e) => write!(f, "{}", e),
+            ExecuteError::ListError(x) => write!(f, "could not read packages from {}", x),
+        }
+    }
+}
+
+impl Error for ExecuteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExecuteError::EditError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<EditError> for ExecuteError {
+    fn from(e: EditError) -> Self {
+        ExecuteError::EditError(e)
+    }
+}
+
+/// Reasons editing a configuration file with nix-editor can fail
+///
+/// The original nix-editor error is kept and can be retrieved with [`EditError::inner`].
+#[derive(Debug)]
+pub enum EditError {
+    /// The file could not be parsed as a nix configuration.
+    ParseError(WriteError),
+    /// The attribute does not exist in the file.
+    /// The attribute path is included.
+    NoAttr(String, WriteError),
+    /// The attribute exists but is not a list.
+    /// The attribute path is included.
+    NotAList(String, WriteError),
+    /// The attribute is an attribute set and cannot hold a value.
+    /// The attribute path is included.
+    WriteValueToSet(String, WriteError),
+}
+
+impl EditError {
+    fn new(e: WriteError, attr: &str) -> Self {
+        match e {
+            WriteError::ParseError => EditError::ParseError(e),
+            WriteError::NoAttr => EditError::NoAttr(attr.to_string(), e),
+            WriteError::ArrayError => EditError::NotAList(attr.to_string(), e),
+            WriteError::WriteValueToSet => EditError::WriteValueToSet(attr.to_string(), e),
+        }
+    }
+
+    /// Returns the error reported by nix-editor
+    pub fn inner(&self) -> &WriteError {
+        match self {
+            EditError::ParseError(e)
+            | EditError::NoAttr(_, e)
+            | EditError::NotAList(_, e)
+            | EditError::WriteValueToSet(_, e) => e,
+        }
+    }
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::ParseError(_) => write!(f, "failed to parse file as a nix configuration"),
+            EditError::NoAttr(attr, _) => write!(f, "attribute '{}' does not exist", attr),
+            EditError::NotAList(attr, _) => write!(f, "attribute '{}' is not a list", attr),
+            EditError::WriteValueToSet(attr, _) => {
+                write!(f, "attribute '{}' is an attribute set", attr)
+            }
+        }
+    }
+}
+
+impl Error for EditError {}
+
+/// Describes a command that could not be started or exited unsuccessfully
+#[derive(Debug, Clone)]
+pub struct CmdFailure {
+    /// The full command line that was run
+    pub cmd: String,
+    /// The exit code of the command.
+    /// `None` if the command could not be started or was killed by a signal.
+    pub code: Option<i32>,
+    /// The last lines the command wrote to stderr
+    pub stderr: String,
+}
+
+impl fmt::Display for CmdFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "'{}' exited with code {}", self.cmd, code)?,
+            None => write!(f, "'{}' did not exit normally", self.cmd)?,
+        }
+        if !self.stderr.is_empty() {
+            write!(f, "\n{}", self.stderr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Installs packages using `nix-env -iA <channel>.<pkg>`
 ///
-/// Packages must be in the `nixos` channel
-pub fn envinstall(pkgs: Vec<String>) -> Result<(), ExecuteError> {
+/// Packages are taken from `channel`, unless given as `<channel>:<pkg>`
+pub fn envinstall(
+    runner: &dyn CommandRunner,
+    pkgs: Vec<String>,
+    channel: &str,
+) -> Result<(), ExecuteError> {
     let mut prefixpkgs = vec![];
     for p in &pkgs {
-        prefixpkgs.push(format!("nixos.{}", p));
-    }
-    match Command::new("nix-env").arg("-iA").args(prefixpkgs).status() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
+        match p.split_once(':') {
+            Some((c, attr)) => prefixpkgs.push(format!("{}.{}", c, attr)),
+            None => prefixpkgs.push(format!("{}.{}", channel, p)),
+        }
     }
+    runner.run(Command::new("nix-env").arg("-iA").args(prefixpkgs))
+}
+
+/// Lists the names of the channels shown by `nix-channel --list`
+pub fn listchannels(runner: &dyn CommandRunner) -> Result<Vec<String>, ExecuteError> {
+    let out = runner.output(Command::new("nix-channel").arg("--list"))?;
+    Ok(String::from_utf8_lossy(&out)
+        .lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .map(|x| x.to_string())
+        .collect())
 }
 
 /// Uninstalls packages using `nix-env -e <pkg>`
 ///
 /// Uninstalling is based on the package name rather than the package attribute used during install
-pub fn envremove(pkgs: Vec<String>) -> Result<(), ExecuteError> {
-    match Command::new("nix-env").arg("-e").args(pkgs).status() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
-    }
+pub fn envremove(runner: &dyn CommandRunner, pkgs: Vec<String>) -> Result<(), ExecuteError> {
+    runner.run(Command::new("nix-env").arg("-e").args(pkgs))
 }
 
 /// Updates packages using `nix-env -u *`
-pub fn envupdate() -> Result<(), ExecuteError> {
-    match Command::new("nix-env").arg("-u").arg("*").status() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
+pub fn envupdate(runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+    runner.run(Command::new("nix-env").arg("-u").arg("*"))
+}
+
+/// Installs packages using `nix profile install nixpkgs#<pkg>`
+///
+/// Packages are taken from the `nixpkgs` flake in the registry
+pub fn profileinstall(runner: &dyn CommandRunner, pkgs: Vec<String>) -> Result<(), ExecuteError> {
+    let mut prefixpkgs = vec![];
+    for p in &pkgs {
+        prefixpkgs.push(format!("nixpkgs#{}", p));
     }
+    runner.run(
+        Command::new("nix")
+            .arg("profile")
+            .arg("install")
+            .args(prefixpkgs),
+    )
+}
+
+/// Removes packages using `nix profile remove <element>`
+///
+/// Elements are the names or indices listed by `nix profile list`, not package attributes
+pub fn profileremove(runner: &dyn CommandRunner, elems: Vec<String>) -> Result<(), ExecuteError> {
+    runner.run(Command::new("nix").arg("profile").arg("remove").args(elems))
+}
+
+/// Upgrades all packages using `nix profile upgrade --all`
+pub fn profileupgrade(runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+    runner.run(
+        Command::new("nix")
+            .arg("profile")
+            .arg("upgrade")
+            .arg("--all"),
+    )
 }
 
 /// Adds packages to a nix configuration file
@@ -52,12 +207,10 @@ pub fn pkwrite(
 ) -> Result<String, ExecuteError> {
     let q;
     (pkgs, q) = pkwith(pkgs, f, query);
-    let out = match nix_editor::write::addtoarr(f, &q, pkgs) {
-        Ok(x) => x,
-        Err(_) => exit(1),
-    };
-
-    Ok(out)
+    match nix_editor::write::addtoarr(f, &q, pkgs) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(EditError::new(e, &q).into()),
+    }
 }
 
 /// Removes packages from a nix configuration file
@@ -68,102 +221,89 @@ pub fn pkwrite(
 pub fn pkrm(mut pkgs: Vec<String>, f: &str, query: Option<&str>) -> Result<String, ExecuteError> {
     let q;
     (pkgs, q) = pkwith(pkgs, f, query);
-    let out = match nix_editor::write::rmarr(f, &q, pkgs) {
-        Ok(x) => x,
-        Err(_) => exit(1),
-    };
-
-    Ok(out)
+    match nix_editor::write::rmarr(f, &q, pkgs) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(EditError::new(e, &q).into()),
+    }
 }
 
 fn pkwith(mut pkgs: Vec<String>, f: &str, query: Option<&str>) -> (Vec<String>, String) {
     let q = query.unwrap_or("environment.systemPackages");
 
-    if let Ok(s) = nix_editor::read::getwithvalue(f, q) {
-        if !s.contains(&"pkgs".to_string()) {
-            pkgs = pkgs
-                .into_iter()
-                .map(|x| format!("pkgs.{}", x))
-                .collect::<Vec<String>>();
-        }
+    // Lists without `with pkgs;`, including ones that do not exist yet, need the `pkgs.` prefix
+    let withpkgs = match nix_editor::read::getwithvalue(f, q) {
+        Ok(s) => s.contains(&"pkgs".to_string()),
+        Err(_) => false,
+    };
+    if !withpkgs {
+        pkgs = pkgs
+            .into_iter()
+            .map(|x| format!("pkgs.{}", x))
+            .collect::<Vec<String>>();
     }
 
     (pkgs, q.to_string())
 }
 
 /// Calls `nixos-rebuild switch`
-pub fn systemswitch() -> Result<(), ExecuteError> {
-    match Command::new("nixos-rebuild")
-        .arg("switch")
-        .arg("--use-remote-sudo")
-        .status()
-    {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
-    }
+pub fn systemswitch(runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+    runner.run(
+        Command::new("nixos-rebuild")
+            .arg("switch")
+            .arg("--use-remote-sudo"),
+    )
 }
 
 /// Calls `nixos-rebuild switch` with the `--flake` flag
 ///
 /// The input `flakepath` is the path to the flake file with any arguments.
 /// Eg `/etc/nixos#user`.
-pub fn systemflakeswitch(flakepath: &str) -> Result<(), ExecuteError> {
-    let status = Command::new("nixos-rebuild")
-        .arg("switch")
-        .arg("--flake")
-        .arg(flakepath)
-        .arg("--use-remote-sudo")
-        .status();
-    match status {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
-    }
+pub fn systemflakeswitch(runner: &dyn CommandRunner, flakepath: &str) -> Result<(), ExecuteError> {
+    runner.run(
+        Command::new("nixos-rebuild")
+            .arg("switch")
+            .arg("--flake")
+            .arg(flakepath)
+            .arg("--use-remote-sudo"),
+    )
 }
 
 /// Calls `home-manager switch`
-pub fn homeswitch() -> Result<(), ExecuteError> {
-    let status = Command::new("home-manager").arg("switch").status();
-    match status {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
-    }
+pub fn homeswitch(runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+    runner.run(Command::new("home-manager").arg("switch"))
 }
 
 /// Calls `home-manager switch` with the `--flake` flag
 ///
 /// The input `flakepath` is the path to the flake file with any arguments.
 /// Eg `/home/user/nix#user`.
-pub fn homeflakeswitch(flakepath: &str) -> Result<(), ExecuteError> {
-    let status = Command::new("home-manager")
-        .arg("switch")
-        .arg("--flake")
-        .arg(flakepath)
-        .status();
-    match status {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
-    }
+pub fn homeflakeswitch(runner: &dyn CommandRunner, flakepath: &str) -> Result<(), ExecuteError> {
+    runner.run(
+        Command::new("home-manager")
+            .arg("switch")
+            .arg("--flake")
+            .arg(flakepath),
+    )
 }
 
 /// Calls `nix-channel --update`
-pub fn updatechannel() -> Result<(), ExecuteError> {
-    match Command::new("nix-channel").arg("--update").status() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
-    }
+pub fn updatechannel(runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+    runner.run(Command::new("nix-channel").arg("--update"))
+}
+
+/// Calls `sudo nix-channel --update` to update the root user's channels
+pub fn updatesyschannel(runner: &dyn CommandRunner) -> Result<(), ExecuteError> {
+    runner.run(Command::new("sudo").arg("nix-channel").arg("--update"))
 }
 
 /// Calls `nix flake update` on the specified flake
 ///
 /// The input `flake` is the path to the flake file.
-pub fn updateflake(flake: &str) -> Result<(), ExecuteError> {
-    match Command::new("nix")
-        .arg("flake")
-        .arg("update")
-        .arg(flake.split('#').collect::<Vec<&str>>()[0])
-        .status()
-    {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ExecuteError::CmdError),
-    }
+pub fn updateflake(runner: &dyn CommandRunner, flake: &str) -> Result<(), ExecuteError> {
+    runner.run(
+        Command::new("nix")
+            .arg("flake")
+            .arg("update")
+            .arg(flake.split('#').collect::<Vec<&str>>()[0]),
+    )
 }
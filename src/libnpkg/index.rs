@@ -0,0 +1,694 @@
+use crate::query::Query;
+use crate::rank::{rank, scorelower, PkgData};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{cmp::Ordering, collections::HashMap, error::Error, fmt, fs, io, path::Path};
+
+/// Identifies a search index and the version of its format
+const MAGIC: &[u8; 8] = b"NPKGIDX4";
+/// Magic, package count and the offsets of the name table and the string blob
+const HEADER: usize = 20;
+/// Strings stored for each package, each as an offset and a length
+const ATTR: usize = 0;
+const NAME: usize = 1;
+const VERSION: usize = 2;
+const DESCRIPTION: usize = 3;
+/// Lowercase attribute and description, separated by a newline
+const KEY: usize = 4;
+const LICENSE: usize = 5;
+const MAINTAINERS: usize = 6;
+const PLATFORMS: usize = 7;
+const HOMEPAGE: usize = 8;
+const LONGDESCRIPTION: usize = 9;
+const OUTPUTS: usize = 10;
+const POSITION: usize = 11;
+const VULNERABILITIES: usize = 12;
+/// Names of the status flags that are set, one per line
+const FLAGS: usize = 13;
+const FIELDS: usize = 14;
+const RECORD: usize = FIELDS * 8;
+/// Length marking a missing description
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub enum IndexError {
+    /// Thrown when reading or writing the index fails.
+    IoError(io::Error),
+    /// Thrown when `packages.json` cannot be parsed.
+    /// The parser error is included.
+    ParseError(String),
+    /// Thrown when a file is not a search index, or was written by an incompatible version.
+    InvalidIndex,
+    /// Thrown when the packages do not fit in a search index, which uses 32 bit offsets.
+    TooLarge,
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexError::IoError(e) => write!(f, "{}", e),
+            IndexError::ParseError(e) => write!(f, "could not parse packages: {}", e),
+            IndexError::InvalidIndex => write!(f, "not a valid search index"),
+            IndexError::TooLarge => write!(f, "too many packages for a search index"),
+        }
+    }
+}
+
+impl Error for IndexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IndexError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for IndexError {
+    fn from(e: io::Error) -> Self {
+        IndexError::IoError(e)
+    }
+}
+
+#[derive(Deserialize)]
+struct PackageBase {
+    packages: HashMap<String, Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+    /// Only present in newer package lists, as an attribute set of output names
+    outputs: Option<Value>,
+    meta: Meta,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    description: Option<String>,
+    long_description: Option<String>,
+    homepage: Option<Value>,
+    license: Option<Value>,
+    maintainers: Option<Value>,
+    platforms: Option<Value>,
+    outputs_to_install: Option<Value>,
+    position: Option<String>,
+    known_vulnerabilities: Option<Value>,
+    broken: Option<bool>,
+    insecure: Option<bool>,
+    unfree: Option<bool>,
+    unsupported: Option<bool>,
+}
+
+/// Keys of a license attribute set that are stored, in order
+const LICENSEKEYS: &[&str] = &["spdxId", "shortName", "fullName"];
+/// Keys of a maintainer attribute set that are stored, in order
+const MAINTAINERKEYS: &[&str] = &["name", "github", "email"];
+
+/// A package in the search index
+#[derive(Debug, PartialEq, Eq)]
+pub struct IndexEntry<'a> {
+    /// Attribute path, e.g. `python3Packages.requests`
+    pub attr: &'a str,
+    /// Full name including the version, e.g. `hello-2.12.1`
+    pub name: &'a str,
+    pub version: &'a str,
+    pub description: Option<&'a str>,
+    /// One license per line, each with its SPDX identifier, short and full name separated by tabs
+    pub license: Option<&'a str>,
+    /// One maintainer per line, each with their name and GitHub handle separated by tabs
+    pub maintainers: Option<&'a str>,
+    /// One platform per line, e.g. `x86_64-linux`, or `*-linux` for a pattern matching any CPU
+    pub platforms: Option<&'a str>,
+    /// One URL per line
+    pub homepage: Option<&'a str>,
+    pub long_description: Option<&'a str>,
+    /// One output per line, e.g. `out` and `man`
+    pub outputs: Option<&'a str>,
+    /// File and line in nixpkgs defining the package, e.g. `pkgs/tools/misc/hello/default.nix:34`
+    pub position: Option<&'a str>,
+    /// One known vulnerability per line
+    pub vulnerabilities: Option<&'a str>,
+    /// The package is marked as broken and will not build
+    pub broken: bool,
+    /// The package has known vulnerabilities
+    pub insecure: bool,
+    /// The package has a license that is not free
+    pub unfree: bool,
+    /// The package is marked as not supported on the system the package list was built for
+    pub unsupported: bool,
+}
+
+/// A license of a package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct License {
+    /// SPDX identifier, e.g. `GPL-3.0-or-later`
+    pub spdx_id: Option<String>,
+    /// Name used in nixpkgs, e.g. `gpl3Plus`
+    pub short_name: Option<String>,
+    pub full_name: Option<String>,
+}
+
+/// A maintainer of a package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Maintainer {
+    pub name: Option<String>,
+    /// GitHub handle
+    pub github: Option<String>,
+    pub email: Option<String>,
+}
+
+/// All metadata of a package in the search index, see [`IndexEntry::info`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PkgInfo {
+    pub attr: String,
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub long_description: Option<String>,
+    pub homepage: Vec<String>,
+    pub licenses: Vec<License>,
+    pub maintainers: Vec<Maintainer>,
+    pub platforms: Vec<String>,
+    pub outputs: Vec<String>,
+    pub position: Option<String>,
+    pub vulnerabilities: Vec<String>,
+    pub broken: bool,
+    pub insecure: bool,
+    pub unfree: bool,
+    pub unsupported: bool,
+}
+
+/// The nix system npkg is running on, e.g. `x86_64-linux` or `aarch64-darwin`
+pub fn currentsystem() -> String {
+    let arch = match std::env::consts::ARCH {
+        "x86" => "i686",
+        "arm" => "armv7l",
+        x => x,
+    };
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        x => x,
+    };
+    format!("{}-{}", arch, os)
+}
+
+impl IndexEntry<'_> {
+    /// Whether the package can be built on `system`, e.g. `x86_64-linux`
+    ///
+    /// Packages without a list of platforms are assumed to build everywhere.
+    pub fn supports(&self, system: &str) -> bool {
+        match self.platforms {
+            Some(x) => x.lines().any(|x| platformmatches(x, system)),
+            None => !self.unsupported,
+        }
+    }
+
+    /// Decodes all metadata of the package
+    pub fn info(&self) -> PkgInfo {
+        let list = |s: Option<&str>| {
+            s.map(|x| x.lines().map(|x| x.to_string()).collect())
+                .unwrap_or_default()
+        };
+        // Attribute sets are stored as their values in a fixed order, empty if missing
+        let values = |line: &str, n: usize| {
+            let mut v = line
+                .split('\t')
+                .map(|x| Some(x.to_string()).filter(|x| !x.is_empty()))
+                .collect::<Vec<_>>();
+            v.resize(n, None);
+            v
+        };
+        PkgInfo {
+            attr: self.attr.to_string(),
+            name: self.name.to_string(),
+            version: self.version.to_string(),
+            description: self.description.map(|x| x.to_string()),
+            long_description: self.long_description.map(|x| x.to_string()),
+            homepage: list(self.homepage),
+            licenses: self
+                .license
+                .unwrap_or_default()
+                .lines()
+                .map(|x| {
+                    // Older packages use the short name of the license as a string
+                    if !x.contains('\t') {
+                        return License {
+                            spdx_id: None,
+                            short_name: Some(x.to_string()),
+                            full_name: None,
+                        };
+                    }
+                    let mut v = values(x, 3).into_iter();
+                    License {
+                        spdx_id: v.next().flatten(),
+                        short_name: v.next().flatten(),
+                        full_name: v.next().flatten(),
+                    }
+                })
+                .collect(),
+            maintainers: self
+                .maintainers
+                .unwrap_or_default()
+                .lines()
+                .map(|x| {
+                    let mut v = values(x, 3).into_iter();
+                    Maintainer {
+                        name: v.next().flatten(),
+                        github: v.next().flatten(),
+                        email: v.next().flatten(),
+                    }
+                })
+                .collect(),
+            platforms: list(self.platforms),
+            outputs: list(self.outputs),
+            position: self.position.map(|x| x.to_string()),
+            vulnerabilities: list(self.vulnerabilities),
+            broken: self.broken,
+            insecure: self.insecure,
+            unfree: self.unfree,
+            unsupported: self.unsupported,
+        }
+    }
+}
+
+/// A compact, memory mapped index of the packages in nixpkgs
+///
+/// Packages are sorted by attribute, and a second table sorts them by name, so both can be
+/// looked up without reading the whole index. Each package also stores its lowercase attribute
+/// and description, so searching does not have to convert case.
+pub struct Index {
+    data: Box<dyn AsRef<[u8]>>,
+    count: usize,
+    names: usize,
+    blob: usize,
+}
+
+/// Builds a search index from the contents of a `packages.json` file
+pub fn buildindex(packages: &[u8]) -> Result<Vec<u8>, IndexError> {
+    let base: PackageBase =
+        serde_json::from_slice(packages).map_err(|e| IndexError::ParseError(e.to_string()))?;
+    let mut pkgs = base.packages.into_iter().collect::<Vec<_>>();
+    pkgs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut records = Vec::with_capacity(pkgs.len() * RECORD);
+    let mut blob = Vec::new();
+    let mut push = |records: &mut Vec<u8>, s: Option<&str>| -> Result<(), IndexError> {
+        let (off, len) = match s {
+            Some(s) => {
+                let off = offset(blob.len())?;
+                blob.extend_from_slice(s.as_bytes());
+                // The largest length marks a missing field
+                match offset(s.len())? {
+                    NONE => return Err(IndexError::TooLarge),
+                    len => (off, len),
+                }
+            }
+            None => (0, NONE),
+        };
+        records.extend_from_slice(&off.to_le_bytes());
+        records.extend_from_slice(&len.to_le_bytes());
+        Ok(())
+    };
+    for (attr, pkg) in &pkgs {
+        let desc = pkg.meta.description.as_deref();
+        // Searches match against the attribute and the description at once
+        let key = format!("{}\n{}", attr, desc.unwrap_or_default()).to_lowercase();
+        push(&mut records, Some(attr))?;
+        push(&mut records, Some(&pkg.name))?;
+        push(&mut records, Some(&pkg.version))?;
+        push(&mut records, desc)?;
+        push(&mut records, Some(&key))?;
+        push(
+            &mut records,
+            lines(&pkg.meta.license, LICENSEKEYS).as_deref(),
+        )?;
+        push(
+            &mut records,
+            lines(&pkg.meta.maintainers, MAINTAINERKEYS).as_deref(),
+        )?;
+        push(&mut records, platforms(&pkg.meta.platforms).as_deref())?;
+        push(&mut records, lines(&pkg.meta.homepage, &[]).as_deref())?;
+        push(&mut records, pkg.meta.long_description.as_deref())?;
+        let outputs = match &pkg.outputs {
+            Some(Value::Object(x)) => Some(x.keys().cloned().collect::<Vec<_>>().join("\n")),
+            _ => lines(&pkg.meta.outputs_to_install, &[]),
+        };
+        push(&mut records, outputs.as_deref())?;
+        push(&mut records, pkg.meta.position.as_deref())?;
+        let vulnerabilities = lines(&pkg.meta.known_vulnerabilities, &[]);
+        push(&mut records, vulnerabilities.as_deref())?;
+        let flags = [
+            ("broken", pkg.meta.broken.unwrap_or(false)),
+            (
+                "insecure",
+                pkg.meta.insecure.unwrap_or(false) || vulnerabilities.is_some(),
+            ),
+            (
+                "unfree",
+                pkg.meta.unfree.unwrap_or(false) || nonfree(&pkg.meta.license),
+            ),
+            ("unsupported", pkg.meta.unsupported.unwrap_or(false)),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(flag, _)| *flag)
+        .collect::<Vec<_>>()
+        .join("\n");
+        push(&mut records, Some(&flags))?;
+    }
+
+    let mut byname = (0..offset(pkgs.len())?).collect::<Vec<u32>>();
+    byname.sort_by(|a, b| pkgs[*a as usize].1.name.cmp(&pkgs[*b as usize].1.name));
+
+    let names = HEADER + records.len();
+    let start = names + byname.len() * 4;
+    let mut out = Vec::with_capacity(start + blob.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&offset(pkgs.len())?.to_le_bytes());
+    out.extend_from_slice(&offset(names)?.to_le_bytes());
+    out.extend_from_slice(&offset(start)?.to_le_bytes());
+    out.extend_from_slice(&records);
+    for i in byname {
+        out.extend_from_slice(&i.to_le_bytes());
+    }
+    out.extend_from_slice(&blob);
+    Ok(out)
+}
+
+/// Converts a position in the index to the 32 bits it is stored in
+fn offset(n: usize) -> Result<u32, IndexError> {
+    u32::try_from(n).map_err(|_| IndexError::TooLarge)
+}
+
+/// Whether any of the licenses is marked as not free
+fn nonfree(license: &Option<Value>) -> bool {
+    let free = |x: &Value| x.get("free") != Some(&Value::Bool(false));
+    match license {
+        Some(Value::Array(x)) => !x.iter().all(free),
+        Some(x) => !free(x),
+        None => false,
+    }
+}
+
+/// Flattens a meta attribute that may be a string, an attribute set or a list of either into lines
+///
+/// Attribute sets are written as the values of `keys` separated by tabs, with missing values left
+/// empty, and skipped if none of the keys are set.
+fn lines(value: &Option<Value>, keys: &[&str]) -> Option<String> {
+    let items = match value {
+        Some(Value::Array(x)) => x.iter().collect::<Vec<_>>(),
+        Some(x) => vec![x],
+        None => vec![],
+    };
+    let mut out = vec![];
+    for item in items {
+        match item {
+            Value::String(x) => out.push(x.to_string()),
+            Value::Object(x) => {
+                let values = keys
+                    .iter()
+                    .map(|k| match x.get(*k) {
+                        Some(Value::String(v)) => v.replace(['\t', '\n'], " "),
+                        _ => String::new(),
+                    })
+                    .collect::<Vec<_>>();
+                if values.iter().any(|x| !x.is_empty()) {
+                    out.push(values.join("\t"));
+                }
+            }
+            _ => {}
+        }
+    }
+    if out.is_empty() {
+        None
+    } else {
+        Some(out.join("\n"))
+    }
+}
+
+/// Flattens `meta.platforms` into lines like [`lines`]
+///
+/// Besides systems like `x86_64-linux`, nixpkgs allows patterns matching parts of the parsed
+/// system, e.g. `{ kernel.name = "linux"; }`. Those are written as `*-linux`, with `*` for any
+/// part the pattern does not name.
+fn platforms(value: &Option<Value>) -> Option<String> {
+    let pattern = |x: &Value| match x {
+        Value::Object(_) => {
+            let parsed = x.get("parsed").unwrap_or(x);
+            let part = |key: &str| {
+                parsed
+                    .get(key)
+                    .and_then(|x| x.get("name"))
+                    .and_then(|x| x.as_str())
+                    .unwrap_or("*")
+                    .to_string()
+            };
+            Value::String(format!("{}-{}", part("cpu"), part("kernel")))
+        }
+        x => x.clone(),
+    };
+    let value = value.as_ref().map(|x| match x {
+        Value::Array(x) => Value::Array(x.iter().map(pattern).collect()),
+        x => pattern(x),
+    });
+    lines(&value, &[])
+}
+
+/// Whether the platform `platform` written by [`platforms`] matches `system`
+fn platformmatches(platform: &str, system: &str) -> bool {
+    match (platform.split_once('-'), system.split_once('-')) {
+        (Some((cpu, kernel)), Some((syscpu, syskernel))) => {
+            (cpu == "*" || cpu == syscpu) && (kernel == "*" || kernel == syskernel)
+        }
+        _ => platform == system,
+    }
+}
+
+/// Builds a search index from the `packages.json` file `packages` and writes it to `index`
+///
+/// The index is written to a temporary file first, so readers never see a partial index.
+pub fn writeindex(packages: &Path, index: &Path) -> Result<(), IndexError> {
+    let data = buildindex(&fs::read(packages)?)?;
+    let tmp = index.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, index)?;
+    Ok(())
+}
+
+impl Index {
+    /// Memory maps the search index at `path`
+    pub fn open(path: &Path) -> Result<Index, IndexError> {
+        let file = fs::File::open(path)?;
+        // Safety: the index is only ever replaced by renaming a new file over it, never modified in place
+        let map = unsafe { Mmap::map(&file)? };
+        Index::new(Box::new(map))
+    }
+
+    /// Reads a search index from memory
+    pub fn from_bytes(data: Vec<u8>) -> Result<Index, IndexError> {
+        Index::new(Box::new(data))
+    }
+
+    fn new(data: Box<dyn AsRef<[u8]>>) -> Result<Index, IndexError> {
+        let bytes = (*data).as_ref();
+        if bytes.len() < HEADER || &bytes[..8] != MAGIC {
+            return Err(IndexError::InvalidIndex);
+        }
+        let count = u32at(bytes, 8) as usize;
+        let names = u32at(bytes, 12) as usize;
+        let blob = u32at(bytes, 16) as usize;
+        if names != HEADER + count * RECORD || blob != names + count * 4 || blob > bytes.len() {
+            return Err(IndexError::InvalidIndex);
+        }
+        Ok(Index {
+            data,
+            count,
+            names,
+            blob,
+        })
+    }
+
+    /// Number of packages in the index
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the `i`th package, ordered by attribute
+    pub fn get(&self, i: usize) -> IndexEntry<'_> {
+        let flags = self
+            .string(i, FLAGS)
+            .unwrap_or_default()
+            .lines()
+            .collect::<Vec<_>>();
+        IndexEntry {
+            attr: self.string(i, ATTR).unwrap_or_default(),
+            name: self.string(i, NAME).unwrap_or_default(),
+            version: self.string(i, VERSION).unwrap_or_default(),
+            description: self.string(i, DESCRIPTION),
+            license: self.string(i, LICENSE),
+            maintainers: self.string(i, MAINTAINERS),
+            platforms: self.string(i, PLATFORMS),
+            homepage: self.string(i, HOMEPAGE),
+            long_description: self.string(i, LONGDESCRIPTION),
+            outputs: self.string(i, OUTPUTS),
+            position: self.string(i, POSITION),
+            vulnerabilities: self.string(i, VULNERABILITIES),
+            broken: flags.contains(&"broken"),
+            insecure: flags.contains(&"insecure"),
+            unfree: flags.contains(&"unfree"),
+            unsupported: flags.contains(&"unsupported"),
+        }
+    }
+
+    /// Returns the packages whose attribute or description contain every term of `query`, ignoring case
+    ///
+    /// Results are ordered by attribute.
+    pub fn search(&self, query: &[String]) -> Vec<IndexEntry<'_>> {
+        let query = query.iter().map(|x| x.to_lowercase()).collect::<Vec<_>>();
+        (0..self.count)
+            .filter(|i| {
+                let key = self.string(*i, KEY).unwrap_or_default();
+                query.iter().all(|q| key.contains(q.as_str()))
+            })
+            .map(|i| self.get(i))
+            .collect()
+    }
+
+    /// Returns the packages matching every term of `query`, best match first
+    ///
+    /// Unlike [`Index::search`], attributes with small typos also match. See [`score`](crate::score).
+    pub fn rank(&self, query: &[String]) -> Vec<PkgData> {
+        self.scored(query, |_| true)
+    }
+
+    /// Returns the packages matching a parsed [`Query`], best match first
+    ///
+    /// Packages are ranked by the plain terms of the query, if there are none every match scores 0
+    /// and results are ordered by attribute length.
+    pub fn query(&self, query: &Query) -> Vec<PkgData> {
+        self.scored(&query.terms, |pkg| query.filter.matches(pkg))
+    }
+
+    fn scored(&self, query: &[String], filter: impl Fn(&IndexEntry) -> bool) -> Vec<PkgData> {
+        let system = currentsystem();
+        let query = query.iter().map(|x| x.to_lowercase()).collect::<Vec<_>>();
+        let mut out = vec![];
+        for i in 0..self.count {
+            let key = self.string(i, KEY).unwrap_or_default();
+            let (attr, desc) = key.split_once('\n').unwrap_or((key, ""));
+            if let Some(score) = scorelower(&query, attr, desc) {
+                let pkg = self.get(i);
+                if !filter(&pkg) {
+                    continue;
+                }
+                out.push(PkgData {
+                    pname: pkg.attr.to_string(),
+                    description: pkg.description.map(|x| x.to_string()),
+                    version: pkg.version.to_string(),
+                    score,
+                    broken: pkg.broken,
+                    insecure: pkg.insecure,
+                    unfree: pkg.unfree,
+                    unsupported: !pkg.supports(&system),
+                });
+            }
+        }
+        rank(&mut out);
+        out
+    }
+
+    /// Returns up to `limit` attributes similar to `attr`, most similar first
+    ///
+    /// Used to suggest what was meant when `attr` does not exist. Attributes are compared as a
+    /// whole and by their last part, so `requests` suggests `python3Packages.requests`.
+    pub fn suggest(&self, attr: &str, limit: usize) -> Vec<&str> {
+        let attr = attr.to_lowercase();
+        let name = attr.rsplit('.').next().unwrap_or(&attr);
+        // Short attributes are similar to too many others with more than one typo
+        let allowed = match attr.chars().count() {
+            0..=2 => 0,
+            3..=5 => 1,
+            6..=9 => 2,
+            _ => 3,
+        };
+        let mut found = vec![];
+        for i in 0..self.count {
+            let candidate = self.string(i, ATTR).unwrap_or_default();
+            let lower = candidate.to_lowercase();
+            let last = lower.rsplit('.').next().unwrap_or(&lower);
+            let dist = strsim::damerau_levenshtein(&attr, &lower)
+                .min(strsim::damerau_levenshtein(name, last) + 1);
+            if dist <= allowed {
+                found.push((dist, candidate));
+            }
+        }
+        found.sort_by(|a, b| (a.0, a.1.len(), a.1).cmp(&(b.0, b.1.len(), b.1)));
+        found.into_iter().take(limit).map(|x| x.1).collect()
+    }
+
+    /// Looks up a package by attribute
+    pub fn byattr(&self, attr: &str) -> Option<IndexEntry<'_>> {
+        self.bisect(
+            |i| self.string(i, ATTR).unwrap_or_default().cmp(attr),
+            |i| i,
+        )
+    }
+
+    /// Looks up a package by its full name, e.g. `hello-2.12.1`
+    pub fn byname(&self, name: &str) -> Option<IndexEntry<'_>> {
+        let bytes = (*self.data).as_ref();
+        let order = |i: usize| u32at(bytes, self.names + i * 4) as usize;
+        self.bisect(
+            |i| self.string(order(i), NAME).unwrap_or_default().cmp(name),
+            order,
+        )
+    }
+
+    /// Binary searches positions `0..len` with `cmp`, returning the package at `map(position)`
+    fn bisect(
+        &self,
+        cmp: impl Fn(usize) -> Ordering,
+        map: impl Fn(usize) -> usize,
+    ) -> Option<IndexEntry<'_>> {
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match cmp(mid) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Some(self.get(map(mid))),
+            }
+        }
+        None
+    }
+
+    /// Returns string `field` of package `i`, `None` if it is missing or out of bounds
+    fn string(&self, i: usize, field: usize) -> Option<&str> {
+        if i >= self.count {
+            return None;
+        }
+        let bytes = (*self.data).as_ref();
+        let pos = HEADER + i * RECORD + field * 8;
+        let off = u32at(bytes, pos);
+        let len = u32at(bytes, pos + 4);
+        if len == NONE {
+            return None;
+        }
+        let start = self.blob + off as usize;
+        let s = bytes.get(start..start + len as usize)?;
+        std::str::from_utf8(s).ok()
+    }
+}
+
+fn u32at(bytes: &[u8], pos: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[pos..pos + 4]);
+    u32::from_le_bytes(buf)
+}
@@ -1,7 +1,35 @@
+mod backend;
+mod download;
 mod execute;
+mod index;
+mod query;
+mod rank;
+mod runner;
+mod select;
+pub use backend::cfgfiles;
+pub use backend::cfgpkgs;
+pub use backend::defaultchannel;
+pub use backend::profileelems;
+pub use backend::Backend;
+pub use backend::Env;
+pub use backend::FileEdit;
+pub use backend::Home;
+pub use backend::Profile;
+pub use backend::System;
+pub use download::checksum;
+pub use download::download;
+pub use download::extract;
+pub use download::Compression;
+pub use download::DownloadError;
+pub use download::DownloadOptions;
+pub use download::NixosVersion;
 pub use execute::envinstall;
 pub use execute::envremove;
 pub use execute::envupdate;
+pub use execute::listchannels;
+pub use execute::profileinstall;
+pub use execute::profileremove;
+pub use execute::profileupgrade;
 pub use execute::pkwrite;
 pub use execute::pkrm;
 pub use execute::systemswitch;
@@ -9,5 +37,30 @@ pub use execute::systemflakeswitch;
 pub use execute::homeswitch;
 pub use execute::homeflakeswitch;
 pub use execute::updatechannel;
+pub use execute::updatesyschannel;
 pub use execute::updateflake;
-pub use execute::ExecuteError;
\ No newline at end of file
+pub use execute::CmdFailure;
+pub use execute::EditError;
+pub use execute::ExecuteError;
+pub use index::buildindex;
+pub use index::currentsystem;
+pub use index::writeindex;
+pub use index::Index;
+pub use index::IndexEntry;
+pub use index::IndexError;
+pub use index::License;
+pub use index::Maintainer;
+pub use index::PkgInfo;
+pub use query::Field;
+pub use query::Filter;
+pub use query::Pattern;
+pub use query::Query;
+pub use query::QueryError;
+pub use rank::rank;
+pub use rank::score;
+pub use rank::PkgData;
+pub use runner::CommandRunner;
+pub use runner::FakeRunner;
+pub use runner::SystemRunner;
+pub use select::selection;
+pub use select::SelectionError;
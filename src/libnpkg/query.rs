@@ -0,0 +1,161 @@
+use crate::index::IndexEntry;
+use regex::{Regex, RegexBuilder};
+use std::{error::Error, fmt};
+
+#[derive(Debug)]
+pub enum QueryError {
+    /// Thrown when a `/regex/` term does not compile.
+    /// The term and the regex error are included.
+    InvalidRegex(String, String),
+    /// Thrown when a term is qualified with an unknown field, e.g. `foo:bar`.
+    UnknownField(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::InvalidRegex(term, e) => write!(f, "invalid regex '{}': {}", term, e),
+            QueryError::UnknownField(field) => write!(
+                f,
+                "unknown field '{}', expected one of name, desc, version, license, maintainer or platform",
+                field
+            ),
+        }
+    }
+}
+
+impl Error for QueryError {}
+
+/// The package metadata a term is matched against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// The attribute or the description, used for terms without a field
+    Any,
+    /// The attribute or the full name
+    Name,
+    Description,
+    Version,
+    License,
+    Maintainer,
+    Platform,
+}
+
+/// What a field has to contain
+#[derive(Debug)]
+pub enum Pattern {
+    /// Lowercase text the field contains, ignoring case
+    Text(String),
+    /// A case insensitive regular expression
+    Regex(Regex),
+}
+
+/// A tree of conditions a package has to satisfy
+#[derive(Debug)]
+pub enum Filter {
+    /// Every filter matches
+    All(Vec<Filter>),
+    /// The filter does not match
+    Not(Box<Filter>),
+    Match(Field, Pattern),
+}
+
+/// A parsed search query
+///
+/// Every term has to match. Terms can be
+/// - plain text, matched against the attribute and description and used to rank results
+/// - `/regex/`, a case insensitive regular expression
+/// - `field:value` or `field:/regex/`, where field is one of `name`, `desc`, `version`,
+///   `license`, `maintainer` or `platform`
+/// - any of the above prefixed with `-` or `!` to exclude packages that match
+#[derive(Debug)]
+pub struct Query {
+    /// Plain text terms, which are matched with typos and used for ranking
+    pub terms: Vec<String>,
+    /// All other terms
+    pub filter: Filter,
+}
+
+impl Query {
+    /// Parses search terms, each element of `terms` being one term
+    pub fn parse(terms: &[String]) -> Result<Query, QueryError> {
+        let mut plain = vec![];
+        let mut filters = vec![];
+        for term in terms {
+            let (negated, rest) = match term.strip_prefix(['-', '!']) {
+                Some(x) if !x.is_empty() => (true, x),
+                _ => (false, term.as_str()),
+            };
+            let (field, value) = match rest.split_once(':') {
+                Some((f, v)) if !f.is_empty() && f.chars().all(|c| c.is_ascii_alphabetic()) => {
+                    (parsefield(f)?, v)
+                }
+                _ => (Field::Any, rest),
+            };
+            let pattern = match value.strip_prefix('/').and_then(|x| x.strip_suffix('/')) {
+                Some(re) => Pattern::Regex(
+                    RegexBuilder::new(re)
+                        .case_insensitive(true)
+                        .build()
+                        .map_err(|e| QueryError::InvalidRegex(value.to_string(), e.to_string()))?,
+                ),
+                None => Pattern::Text(value.to_lowercase()),
+            };
+
+            match (negated, field, pattern) {
+                (false, Field::Any, Pattern::Text(x)) => plain.push(x),
+                (false, field, pattern) => filters.push(Filter::Match(field, pattern)),
+                (true, field, pattern) => {
+                    filters.push(Filter::Not(Box::new(Filter::Match(field, pattern))))
+                }
+            }
+        }
+        Ok(Query {
+            terms: plain,
+            filter: Filter::All(filters),
+        })
+    }
+}
+
+fn parsefield(field: &str) -> Result<Field, QueryError> {
+    match field.to_lowercase().as_str() {
+        "name" | "attr" => Ok(Field::Name),
+        "desc" | "description" => Ok(Field::Description),
+        "version" => Ok(Field::Version),
+        "license" => Ok(Field::License),
+        "maintainer" => Ok(Field::Maintainer),
+        "platform" => Ok(Field::Platform),
+        _ => Err(QueryError::UnknownField(field.to_string())),
+    }
+}
+
+impl Filter {
+    /// Whether the package `pkg` satisfies the filter
+    pub fn matches(&self, pkg: &IndexEntry) -> bool {
+        match self {
+            Filter::All(x) => x.iter().all(|f| f.matches(pkg)),
+            Filter::Not(x) => !x.matches(pkg),
+            Filter::Match(field, pattern) => {
+                let values = match field {
+                    Field::Any => vec![Some(pkg.attr), pkg.description],
+                    Field::Name => vec![Some(pkg.attr), Some(pkg.name)],
+                    Field::Description => vec![pkg.description],
+                    Field::Version => vec![Some(pkg.version)],
+                    Field::License => vec![pkg.license],
+                    Field::Maintainer => vec![pkg.maintainers],
+                    Field::Platform => vec![pkg.platforms],
+                };
+                values.into_iter().flatten().any(|x| pattern.matches(x))
+            }
+        }
+    }
+}
+
+impl Pattern {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Pattern::Text(x) => value.to_lowercase().contains(x.as_str()),
+            // Multi-valued fields are stored one value per line
+            Pattern::Regex(x) => value.lines().any(|line| x.is_match(line)),
+        }
+    }
+}
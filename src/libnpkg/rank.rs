@@ -0,0 +1,117 @@
+use std::cmp::Reverse;
+
+/// A package found by a search
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PkgData {
+    pub pname: String,
+    pub description: Option<String>,
+    pub version: String,
+    /// Relevance to the search query, higher is better
+    pub score: u32,
+    /// The package is marked as broken and will not build
+    pub broken: bool,
+    /// The package has known vulnerabilities
+    pub insecure: bool,
+    /// The package has a license that is not free
+    pub unfree: bool,
+    /// The package does not support the system npkg is running on
+    pub unsupported: bool,
+}
+
+/// The whole attribute, or its last component, equals the term
+const EXACT: u32 = 1000;
+/// The attribute starts with the term, shorter attributes score higher
+const PREFIX: u32 = 600;
+/// A part of the attribute separated by `.`, `-` or `_` equals the term
+const TOKEN: u32 = 400;
+/// A part of the attribute starts with the term
+const TOKENPREFIX: u32 = 300;
+/// The term appears somewhere in the attribute
+const SUBSTRING: u32 = 200;
+/// The term is a typo of a part of the attribute, minus a penalty per edit
+const FUZZY: u32 = 100;
+/// The term is a word of the description
+const DESCWORD: u32 = 80;
+/// The term appears somewhere in the description
+const DESCSUBSTRING: u32 = 40;
+
+/// Scores how well a package matches every term of `query`, ignoring case
+///
+/// Returns `None` if any term matches neither the attribute nor the description.
+pub fn score(query: &[String], attr: &str, description: Option<&str>) -> Option<u32> {
+    let query = query.iter().map(|x| x.to_lowercase()).collect::<Vec<_>>();
+    scorelower(
+        &query,
+        &attr.to_lowercase(),
+        &description.unwrap_or_default().to_lowercase(),
+    )
+}
+
+/// Same as [`score`], with the query, attribute and description already in lowercase
+pub(crate) fn scorelower(query: &[String], attr: &str, description: &str) -> Option<u32> {
+    let mut total = 0;
+    for q in query {
+        match termscore(q, attr, description) {
+            0 => return None,
+            x => total += x,
+        }
+    }
+    Some(total)
+}
+
+fn termscore(q: &str, attr: &str, desc: &str) -> u32 {
+    let name = attr.rsplit('.').next().unwrap_or(attr);
+    let tokens = attr.split(['.', '-', '_']).filter(|x| !x.is_empty());
+
+    let attrscore = if attr == q || name == q {
+        EXACT
+    } else if name.starts_with(q) || attr.starts_with(q) {
+        PREFIX - (name.len().saturating_sub(q.len()).min(100) as u32)
+    } else if tokens.clone().any(|x| x == q) {
+        TOKEN
+    } else if tokens.clone().any(|x| x.starts_with(q)) {
+        TOKENPREFIX
+    } else if attr.contains(q) {
+        SUBSTRING
+    } else {
+        // Short terms match too many attributes with a typo to be useful
+        let allowed = match q.chars().count() {
+            0..=2 => 0,
+            3..=7 => 1,
+            _ => 2,
+        };
+        let dist = tokens
+            .chain(std::iter::once(name))
+            .map(|x| strsim::damerau_levenshtein(q, x))
+            .min()
+            .unwrap_or(usize::MAX);
+        if dist <= allowed {
+            FUZZY - 30 * dist as u32
+        } else {
+            0
+        }
+    };
+
+    let descscore = if desc.split(|c: char| !c.is_alphanumeric()).any(|x| x == q) {
+        DESCWORD
+    } else if desc.contains(q) {
+        DESCSUBSTRING
+    } else {
+        0
+    };
+
+    attrscore + descscore
+}
+
+/// Sorts packages by score, best first
+///
+/// Packages with the same score are ordered by attribute length and then alphabetically.
+pub fn rank(pkgs: &mut [PkgData]) {
+    pkgs.sort_by(|a, b| {
+        (Reverse(a.score), a.pname.len(), &a.pname).cmp(&(
+            Reverse(b.score),
+            b.pname.len(),
+            &b.pname,
+        ))
+    });
+}
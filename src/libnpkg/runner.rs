@@ -0,0 +1,176 @@
+use crate::execute::{CmdFailure, ExecuteError};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{BufRead, BufReader, IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+/// Number of stderr lines kept when a command fails
+const STDERR_TAIL: usize = 20;
+
+/// Runs external commands on behalf of npkg
+///
+/// Every call to `nix-env`, `nixos-rebuild`, `home-manager` and friends goes through a runner,
+/// so the real commands can be swapped out for [`FakeRunner`] in tests.
+pub trait CommandRunner {
+    /// Runs `cmd` with its output shown to the user
+    ///
+    /// Returns an error if the command cannot be started or exits with a non-zero status.
+    fn run(&self, cmd: &mut Command) -> Result<(), ExecuteError>;
+
+    /// Runs `cmd` and returns everything it wrote to stdout
+    ///
+    /// Returns an error if the command cannot be started or exits with a non-zero status.
+    fn output(&self, cmd: &mut Command) -> Result<Vec<u8>, ExecuteError>;
+}
+
+/// Returns the command line of `cmd` as a single string
+fn cmdline(cmd: &Command) -> String {
+    let mut out = vec![cmd.get_program().to_string_lossy().to_string()];
+    for a in cmd.get_args() {
+        out.push(a.to_string_lossy().to_string());
+    }
+    out.join(" ")
+}
+
+/// Runs commands on the host system
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    /// Stdout is inherited. When stderr is not a terminal it is forwarded line by line and the
+    /// last lines are kept in the error.
+    ///
+    /// A terminal is passed on as is instead, so nix keeps its progress bar and colors during
+    /// `nixos-rebuild` and `home-manager switch`. The error then only names the command, its
+    /// output is already on screen.
+    fn run(&self, cmd: &mut Command) -> Result<(), ExecuteError> {
+        let line = cmdline(cmd);
+        if !std::io::stderr().is_terminal() {
+            cmd.stderr(Stdio::piped());
+        }
+        let mut child = match cmd.spawn() {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(ExecuteError::CmdError(CmdFailure {
+                    cmd: line,
+                    code: None,
+                    stderr: e.to_string(),
+                }))
+            }
+        };
+
+        let mut tail = VecDeque::new();
+        if let Some(stderr) = child.stderr.take() {
+            let mut err = std::io::stderr();
+            for l in BufReader::new(stderr).split(b'\n').flatten() {
+                let _ = err.write_all(&l);
+                let _ = err.write_all(b"\n");
+                if tail.len() == STDERR_TAIL {
+                    tail.pop_front();
+                }
+                tail.push_back(String::from_utf8_lossy(&l).to_string());
+            }
+        }
+
+        match child.wait() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(ExecuteError::CmdError(CmdFailure {
+                cmd: line,
+                code: status.code(),
+                stderr: Vec::from(tail).join("\n"),
+            })),
+            Err(e) => Err(ExecuteError::CmdError(CmdFailure {
+                cmd: line,
+                code: None,
+                stderr: e.to_string(),
+            })),
+        }
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Vec<u8>, ExecuteError> {
+        let line = cmdline(cmd);
+        match cmd.output() {
+            Ok(x) if x.status.success() => Ok(x.stdout),
+            Ok(x) => {
+                let stderr = String::from_utf8_lossy(&x.stderr);
+                let lines = stderr.lines().collect::<Vec<&str>>();
+                Err(ExecuteError::CmdError(CmdFailure {
+                    cmd: line,
+                    code: x.status.code(),
+                    stderr: lines[lines.len().saturating_sub(STDERR_TAIL)..].join("\n"),
+                }))
+            }
+            Err(e) => Err(ExecuteError::CmdError(CmdFailure {
+                cmd: line,
+                code: None,
+                stderr: e.to_string(),
+            })),
+        }
+    }
+}
+
+struct Script {
+    prefix: String,
+    code: i32,
+    stdout: String,
+    stderr: String,
+}
+
+/// A runner that records commands instead of running them
+///
+/// Responses are scripted with [`FakeRunner::respond`].
+/// Commands without a matching script succeed with no output.
+#[derive(Default)]
+pub struct FakeRunner {
+    scripts: RefCell<Vec<Script>>,
+    calls: RefCell<Vec<String>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scripts the response to every command whose command line starts with `prefix`
+    ///
+    /// Later scripts take precedence over earlier ones.
+    pub fn respond(&self, prefix: &str, code: i32, stdout: &str, stderr: &str) {
+        self.scripts.borrow_mut().push(Script {
+            prefix: prefix.to_string(),
+            code,
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+        });
+    }
+
+    /// Returns the command lines run so far, in order
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    fn call(&self, cmd: &Command) -> Result<Vec<u8>, ExecuteError> {
+        let line = cmdline(cmd);
+        self.calls.borrow_mut().push(line.clone());
+        let scripts = self.scripts.borrow();
+        match scripts.iter().rev().find(|s| line.starts_with(&s.prefix)) {
+            Some(s) if s.code != 0 => Err(ExecuteError::CmdError(CmdFailure {
+                cmd: line,
+                code: Some(s.code),
+                stderr: s.stderr.trim_end().to_string(),
+            })),
+            Some(s) => Ok(s.stdout.as_bytes().to_vec()),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run(&self, cmd: &mut Command) -> Result<(), ExecuteError> {
+        self.call(cmd).map(|_| ())
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Vec<u8>, ExecuteError> {
+        self.call(cmd)
+    }
+}
@@ -0,0 +1,70 @@
+use std::{error::Error, fmt};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SelectionError {
+    /// Thrown when a part of the selection is not a number or a range, e.g. `a` or `3-`.
+    Invalid(String),
+    /// Thrown when a number is not between 1 and the number of options.
+    /// The number and the number of options are included.
+    OutOfRange(usize, usize),
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectionError::Invalid(part) => {
+                write!(f, "'{}' is not a number or a range like 5-7", part)
+            }
+            SelectionError::OutOfRange(n, count) => {
+                write!(f, "{} is not between 1 and {}", n, count)
+            }
+        }
+    }
+}
+
+impl Error for SelectionError {}
+
+/// Parses a selection of `count` numbered options, e.g. `1 3 5-7`
+///
+/// Parts are separated by spaces or commas and are either a number or a range `N-M`. Parts
+/// prefixed with `^` exclude options instead, e.g. `1-10 ^4`. If only exclusions are given, all
+/// other options are selected. Returns the selected options counting from 0 in ascending order,
+/// an empty selection selects nothing.
+pub fn selection(answer: &str, count: usize) -> Result<Vec<usize>, SelectionError> {
+    let mut included = vec![false; count];
+    let mut excluded = vec![false; count];
+    let mut anyincluded = false;
+    for part in answer
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|x| !x.is_empty())
+    {
+        let (exclude, range) = match part.strip_prefix('^') {
+            Some(x) => (true, x),
+            None => (false, part),
+        };
+        let number = |x: &str| match x.parse::<usize>() {
+            Ok(n) if (1..=count).contains(&n) => Ok(n - 1),
+            Ok(n) => Err(SelectionError::OutOfRange(n, count)),
+            Err(_) => Err(SelectionError::Invalid(part.to_string())),
+        };
+        let (first, last) = match range.split_once('-') {
+            Some((a, b)) => (number(a)?, number(b)?),
+            None => (number(range)?, number(range)?),
+        };
+        // Ranges may be given backwards, e.g. `7-5`
+        for i in first.min(last)..=first.max(last) {
+            if exclude {
+                excluded[i] = true;
+            } else {
+                included[i] = true;
+            }
+        }
+        anyincluded |= !exclude;
+    }
+    if !anyincluded && excluded.contains(&true) {
+        included = vec![true; count];
+    }
+    Ok((0..count)
+        .filter(|&i| included[i] && !excluded[i])
+        .collect())
+}
@@ -0,0 +1,199 @@
+use npkg::{Backend, CommandRunner, ExecuteError};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::{self, fs::MetadataExt},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Number of backups kept for each configuration file
+const MAXBACKUPS: usize = 20;
+
+/// A previous version of a configuration file
+pub struct Backup {
+    pub path: PathBuf,
+    /// File the backup was taken from, not known for backups made by older versions
+    pub file: Option<PathBuf>,
+    /// Seconds since the unix epoch when the backup was taken
+    pub time: u64,
+}
+
+/// Returns the directory backups of `backend` are stored in, `$XDG_STATE_HOME/npkg/backups/system`
+/// for the system by default
+pub fn backupdir(backend: &dyn Backend) -> PathBuf {
+    let statedir = match env::var("XDG_STATE_HOME") {
+        Ok(x) if !x.is_empty() => PathBuf::from(x),
+        _ => Path::new(&env::var("HOME").unwrap()).join(".local/state"),
+    };
+    statedir.join("npkg/backups").join(backend.source())
+}
+
+/// Saves `content` as a new backup of the configuration file `file`
+///
+/// Only the newest backups are kept.
+pub fn backup(backend: &dyn Backend, file: &str, content: &str) -> io::Result<PathBuf> {
+    let dir = backupdir(backend);
+    fs::create_dir_all(&dir)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    // The full path is kept in the name so the backup can be restored to the file it came from
+    let name = fs::canonicalize(file)
+        .unwrap_or_else(|_| PathBuf::from(file))
+        .to_string_lossy()
+        .replace('/', "%");
+    let path = dir.join(format!(
+        "{}.{:09}-{}",
+        now.as_secs(),
+        now.subsec_nanos(),
+        name
+    ));
+    fs::write(&path, content)?;
+
+    for old in backups(backend).iter().skip(MAXBACKUPS) {
+        let _ = fs::remove_file(&old.path);
+    }
+    Ok(path)
+}
+
+/// Lists the backups of a configuration file, newest first
+pub fn backups(backend: &dyn Backend) -> Vec<Backup> {
+    let mut out = vec![];
+    if let Ok(dir) = fs::read_dir(backupdir(backend)) {
+        for entry in dir.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(Ok(time)) = name.split('.').next().map(|x| x.parse::<u64>()) {
+                let file = match name.split_once('-') {
+                    Some((_, x)) if x.starts_with('%') => Some(PathBuf::from(x.replace('%', "/"))),
+                    _ => None,
+                };
+                out.push(Backup {
+                    path: entry.path(),
+                    file,
+                    time,
+                });
+            }
+        }
+    }
+    out.sort_by(|a, b| b.path.cmp(&a.path));
+    out
+}
+
+/// Formats seconds since the unix epoch as a UTC date
+pub fn fmttime(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        y,
+        m,
+        d,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Returns the file a write to `file` has to replace, following symlinks
+///
+/// Replacing a symlink itself would leave the file it points to, e.g. in a dotfiles repository, unchanged.
+fn realpath(file: &str) -> PathBuf {
+    fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file))
+}
+
+/// Returns the temporary file written next to `file` before it is renamed into place
+fn tmppath(file: &Path) -> PathBuf {
+    let name = file
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    file.with_file_name(format!(".{}.npkg-tmp", name))
+}
+
+/// Replaces the contents of `file` atomically
+///
+/// The new contents are written to a temporary file in the same directory, which gets the permissions and owner
+/// of `file` and is then renamed over it.
+pub fn atomicwrite(file: &str, content: &str) -> io::Result<()> {
+    let target = realpath(file);
+    let tmp = tmppath(&target);
+    let res = (|| {
+        let mut f = File::create(&tmp)?;
+        f.write_all(content.as_bytes())?;
+        f.sync_all()?;
+        if let Ok(meta) = fs::metadata(&target) {
+            fs::set_permissions(&tmp, meta.permissions())?;
+            let tmpmeta = fs::metadata(&tmp)?;
+            if (tmpmeta.uid(), tmpmeta.gid()) != (meta.uid(), meta.gid()) {
+                unix::fs::chown(&tmp, Some(meta.uid()), Some(meta.gid()))?;
+            }
+        }
+        fs::rename(&tmp, &target)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+/// Replaces the contents of the root owned `file` atomically using `sudo`
+///
+/// The new contents are staged in `~/.cache/npkg`, copied next to `file`, given the permissions and owner of `file`
+/// and then moved into place.
+pub fn sudowrite(
+    runner: &dyn CommandRunner,
+    file: &str,
+    content: &str,
+) -> Result<(), ExecuteError> {
+    let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
+    if fs::create_dir_all(&cachedir).is_err() {
+        return Err(ExecuteError::WriteError(cachedir));
+    }
+    let target = realpath(file);
+    let name = target
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let staged = format!("{}/{}", cachedir, name);
+    if fs::write(&staged, content).is_err() {
+        return Err(ExecuteError::WriteError(cachedir));
+    }
+    let tmp = tmppath(&target);
+    let res = (|| {
+        runner.run(Command::new("sudo").arg("cp").arg(&staged).arg(&tmp))?;
+        if let Ok(meta) = fs::metadata(&target) {
+            runner.run(
+                Command::new("sudo")
+                    .arg("chown")
+                    .arg(format!("{}:{}", meta.uid(), meta.gid()))
+                    .arg(&tmp),
+            )?;
+            runner.run(
+                Command::new("sudo")
+                    .arg("chmod")
+                    .arg(format!("{:o}", meta.mode() & 0o7777))
+                    .arg(&tmp),
+            )?;
+        }
+        runner.run(Command::new("sudo").arg("mv").arg(&tmp).arg(&target))
+    })();
+    if res.is_err() {
+        let _ = runner.run(Command::new("sudo").arg("rm").arg("-f").arg(&tmp));
+    }
+    let removed = fs::remove_file(&staged);
+    res?;
+    removed.map_err(|_| ExecuteError::WriteError(cachedir))
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::{
-    env,
+    env, fmt,
     fs::{self, File},
     io::Write,
     path::Path,
@@ -9,12 +9,73 @@ use std::{
 use owo_colors::*;
 
 #[derive(Serialize, Deserialize, Debug)]
-struct Config {
-    systemconfig: String,
-    homeconfig: String,
-    flake: Option<String>,
+pub struct Config {
+    pub systemconfig: String,
+    pub homeconfig: String,
+    pub flake: Option<String>,
+    /// Use `nix profile` instead of `nix-env` to manage the user environment
+    #[serde(default)]
+    pub nixprofile: bool,
+    /// Channel nix-env installs packages from, detected from `nix-channel --list` if not set
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// File new system packages are added to, `systemconfig` if not set
+    #[serde(default)]
+    pub systemtarget: Option<String>,
+    /// File new home-manager packages are added to, `homeconfig` if not set
+    #[serde(default)]
+    pub hometarget: Option<String>,
+    /// How the package cache used for searching is built
+    #[serde(default)]
+    pub cache: CacheBuilder,
+    /// URL or local path of the package list the download cache builder uses, with `{release}` and
+    /// `{version}` replaced by the nixpkgs release and version. releases.nixos.org if not set
+    #[serde(default)]
+    pub packagesurl: Option<String>,
+    /// Other channels, flake inputs or `nur` searched alongside the main nixpkgs
+    #[serde(default)]
+    pub sources: Vec<String>,
 }
 
+/// How the package cache used for searching is built
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheBuilder {
+    /// Download the package list of the nixpkgs release from releases.nixos.org
+    #[default]
+    Download,
+    /// Evaluate nixpkgs locally with `nix-env -qaP --json --meta`, including overlays
+    NixEnv,
+    /// Evaluate nixpkgs locally with `nix search --json`, which is faster but only has descriptions
+    NixSearch,
+}
+
+impl fmt::Display for CacheBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheBuilder::Download => write!(f, "download"),
+            CacheBuilder::NixEnv => write!(f, "nix-env"),
+            CacheBuilder::NixSearch => write!(f, "nix-search"),
+        }
+    }
+}
+
+fn defaultconfig() -> Config {
+    Config {
+        systemconfig: "/etc/nixos/configuration.nix".to_string(),
+        homeconfig: format!("{}/.config/nixpkgs/home.nix", env::var("HOME").unwrap()),
+        flake: None,
+        nixprofile: false,
+        channel: None,
+        systemtarget: None,
+        hometarget: None,
+        cache: CacheBuilder::Download,
+        packagesurl: None,
+        sources: vec![],
+    }
+}
+
+#[allow(clippy::needless_return, clippy::useless_format)]
 pub fn checkconfig() -> String {
     let cfgdir = format!("{}/.config/npkg", env::var("HOME").unwrap());
     if !Path::is_file(Path::new(&format!("{}/config.json", &cfgdir))) {
@@ -32,39 +93,28 @@ pub fn checkconfig() -> String {
 fn createconfig() {
     let cfgdir = format!("{}/.config/npkg", env::var("HOME").unwrap());
     fs::create_dir_all(&cfgdir).expect("Failed to create config directory");
-    let config = Config {
-        systemconfig: "/etc/nixos/configuration.nix".to_string(),
-        homeconfig: format!("{}/.config/nixpkgs/home.nix", env::var("HOME").unwrap()),
-        flake: None,
-    };
+    let config = defaultconfig();
     let json = serde_json::to_string_pretty(&config).unwrap();
     let mut file = File::create(format!("{}/config.json", cfgdir)).unwrap();
     file.write_all(json.as_bytes()).unwrap();
 }
 
-pub fn readconfig(cfgdir: String) -> (String, String, Option<String>) {
+#[allow(clippy::needless_return)]
+pub fn readconfig(cfgdir: String) -> Config {
     let file = fs::read_to_string(format!("{}/config.json", cfgdir)).unwrap();
     let config: Config = match serde_json::from_str(&file) {
         Ok(x) => x,
         Err(e) => {
             println!("{} {}","Failed to parse config:".red(), e);
             println!("Using default values");
-            return (
-                "/etc/nixos/configuration.nix".to_string(),
-                format!("{}/.config/nixpkgs/home.nix", env::var("HOME").unwrap()),
-                None,
-            );
+            return defaultconfig();
         }
     };
     if Path::is_file(Path::new(&config.systemconfig)) {
-        return (config.systemconfig, config.homeconfig, config.flake);
+        return config;
     } else {
         println!("{}", "Config file is invalid".bright_red());
         println!("{}", "Using default values".yellow());
-        return (
-            "/etc/nixos/configuration.nix".to_string(),
-            format!("{}/.config/nixpkgs/home.nix", env::var("HOME").unwrap()),
-            None,
-        );
+        return defaultconfig();
     }
 }
@@ -0,0 +1,20 @@
+use owo_colors::*;
+use similar::{ChangeTag, TextDiff};
+
+/// Prints a colored unified diff between the `old` and `new` contents of `file`
+pub fn ppdiff(file: &str, old: &str, new: &str) {
+    let diff = TextDiff::from_lines(old, new);
+    println!("{}", format!("--- {}", file).red().bold());
+    println!("{}", format!("+++ {}", file).green().bold());
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        println!("{}", hunk.header().cyan());
+        for change in hunk.iter_changes() {
+            let line = change.value().trim_end_matches('\n');
+            match change.tag() {
+                ChangeTag::Delete => println!("{}", format!("-{}", line).red()),
+                ChangeTag::Insert => println!("{}", format!("+{}", line).green()),
+                ChangeTag::Equal => println!(" {}", line),
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::Write,
+    os::unix::io::FromRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The original stdout, kept for the JSON document while stdout points to stderr
+static STDOUT: Mutex<Option<File>> = Mutex::new(None);
+
+/// An installed package, as printed by `--list`
+#[derive(Serialize, Clone)]
+pub struct ListEntry {
+    pub package: String,
+    /// One of `system`, `home`, `env` or `profile`
+    pub source: String,
+    /// Configuration file declaring the package, `null` for the nix environment
+    pub file: Option<String>,
+}
+
+/// A search result, as printed by `--search`
+#[derive(Serialize)]
+pub struct SearchEntry {
+    pub pname: String,
+    pub version: String,
+    pub description: Option<String>,
+    /// Relevance to the search query, higher is better
+    pub score: u32,
+    /// Sources the package is installed in
+    pub installed: Vec<String>,
+    pub broken: bool,
+    pub insecure: bool,
+    pub unfree: bool,
+    /// The package does not support the system npkg is running on
+    pub unsupported: bool,
+    /// Sources that offer the package and their version
+    pub sources: Vec<SourceVersion>,
+}
+
+/// The version of a package a source offers
+#[derive(Serialize)]
+pub struct SourceVersion {
+    pub source: String,
+    pub version: String,
+}
+
+/// All metadata of a package, as printed by `--info`
+#[derive(Serialize)]
+pub struct InfoEntry {
+    #[serde(flatten)]
+    pub info: npkg::PkgInfo,
+    /// Where the package is installed
+    pub installed: Vec<ListEntry>,
+}
+
+/// Outcome of an install, remove or update
+#[derive(Serialize, Default)]
+pub struct OpResult {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub action: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub source: String,
+    pub changed: Vec<String>,
+    /// Requested packages that were already installed, or not installed when removing
+    pub skipped: Vec<String>,
+    /// Configuration files that were modified
+    pub files: Vec<String>,
+    pub status: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Switches to JSON output
+///
+/// stdout is reserved for the JSON document, so everything else printed by npkg or by the
+/// commands it runs is sent to stderr instead.
+pub fn enable() {
+    // Safety: only duplicates the standard file descriptors, which stay open for the whole run
+    let stdout = unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+        if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return;
+        }
+        File::from_raw_fd(fd)
+    };
+    *STDOUT.lock().unwrap() = Some(stdout);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Prints `value` as the JSON document
+pub fn emit<T: Serialize>(value: &T) {
+    if let Some(out) = STDOUT.lock().unwrap().as_mut() {
+        let _ = serde_json::to_writer_pretty(&mut *out, value);
+        let _ = writeln!(out);
+    }
+}
+
+/// Prints an error as the JSON document if JSON output is enabled
+pub fn error(msg: &str) {
+    if enabled() {
+        emit(&OpResult {
+            status: 1,
+            error: Some(msg.to_string()),
+            ..Default::default()
+        });
+    }
+}
@@ -1,22 +1,22 @@
+pub mod backup;
 pub mod parse;
 pub mod operate;
 pub mod search;
 pub mod config;
+pub mod diff;
+pub mod json;
+pub mod prompt;
 pub mod run;
 use npkg;
+pub use npkg::PkgData;
 
-pub struct PkgData {
-    pub pname: String,
-    pub description: Option<String>,
-    pub version: String,
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 
 pub enum PackageTypes {
     System,
     Home,
     Env,
+    Profile,
 }
 
 #[derive(Debug)]
@@ -26,7 +26,16 @@ pub struct NpkgData {
     pub output: Option<String>,
     pub syscfg: String,
     pub hmcfg: String,
+    /// Files new packages are added to, may be imported by `syscfg` and `hmcfg`
+    pub systarget: String,
+    pub hmtarget: String,
     pub dryrun: bool,
+    pub yes: bool,
+    pub diffonly: bool,
     pub flake: Option<String>,
-    pub currpkgs: Vec<String>,
+    pub channel: Option<String>,
+    /// List option to edit instead of `environment.systemPackages` or `home.packages`
+    pub attr: Option<String>,
+    /// Packages in `pkgs` already found not to exist, each with suggestions, `None` if not checked
+    pub unknown: Option<Vec<(String, Vec<String>)>>,
 }
\ No newline at end of file
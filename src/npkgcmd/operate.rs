@@ -1,15 +1,31 @@
-use crate::npkgcmd::{search::pname_to_name, NpkgData};
+use crate::npkgcmd::{
+    backup, diff, json,
+    parse::allowsunfree,
+    prompt,
+    search::{self, unfreepkgs, unknownmsg, unknownpkgs},
+    NpkgData,
+    PackageTypes,
+};
 use npkg::*;
 use owo_colors::*;
-use std::{
-    env, fs,
-    path::Path,
-    process::{exit, Command},
-};
+use std::{fs, path::Path, process::exit};
 
 pub enum OperateError {
-    CmdError,
+    CmdError(CmdFailure),
     WriteError(String),
+    EditError(EditError),
+    ListError(String),
+    ReadError(String),
+}
+
+/// Packages affected by an install or remove
+#[derive(Default)]
+pub struct Changes {
+    pub changed: Vec<String>,
+    /// Requested packages that were left alone
+    pub skipped: Vec<String>,
+    /// Configuration files that were modified
+    pub files: Vec<String>,
 }
 
 enum Actions {
@@ -17,300 +33,395 @@ enum Actions {
     Remove,
 }
 
-pub fn pkinstall(opts: NpkgData) -> Result<(), OperateError> {
-    match cfgoperate(opts, Actions::Install) {
-        Ok(()) => Ok(()),
-        Err(e) => Err(e),
+/// Returns the backend managing the packages of `opts.pkgmgr`
+///
+/// nix-env packages are translated with the search index as it is, building the cache first
+/// would make every command wait for it.
+pub fn backend(opts: &NpkgData) -> Box<dyn Backend> {
+    match opts.pkgmgr {
+        PackageTypes::System => Box::new(npkg::System {
+            config: opts.syscfg.to_string(),
+            target: opts.systarget.to_string(),
+            attr: opts.attr.clone(),
+            flake: opts.flake.clone(),
+        }),
+        PackageTypes::Home => Box::new(npkg::Home {
+            config: opts.hmcfg.to_string(),
+            target: opts.hmtarget.to_string(),
+            attr: opts.attr.clone(),
+            flake: opts.flake.clone(),
+        }),
+        PackageTypes::Env => Box::new(npkg::Env {
+            channel: opts.channel.clone(),
+            index: search::cachedindex(),
+        }),
+        PackageTypes::Profile => Box::new(npkg::Profile),
     }
 }
 
-pub fn pkremove(opts: NpkgData) -> Result<(), OperateError> {
-    match cfgoperate(opts, Actions::Remove) {
-        Ok(()) => Ok(()),
-        Err(e) => Err(e),
-    }
+pub fn pkinstall(
+    runner: &dyn CommandRunner,
+    backend: &dyn Backend,
+    opts: NpkgData,
+) -> Result<Changes, OperateError> {
+    operate(runner, backend, opts, Actions::Install)
 }
 
-pub fn chnupdate(opts: &NpkgData) {
+pub fn pkremove(
+    runner: &dyn CommandRunner,
+    backend: &dyn Backend,
+    opts: NpkgData,
+) -> Result<Changes, OperateError> {
+    operate(runner, backend, opts, Actions::Remove)
+}
+
+/// Upgrades the packages of `backend`, rebuilding configurations
+pub fn pkupdate(runner: &dyn CommandRunner, backend: &dyn Backend) -> Result<(), OperateError> {
+    switchmsg(backend);
+    backend.update(runner).map_err(OperateError::from)
+}
+
+pub fn chnupdate(runner: &dyn CommandRunner, opts: &NpkgData) {
     println!("{}", "Updating channels...".green());
-    match updatechannel() {
-        Ok(()) => {}
-        Err(_) => {
-            println!("{}", "Failed to execute process nix-channel".red());
-            exit(1);
-        }
+    if let Err(e) = updatechannel(runner) {
+        println!("{}", "Failed to update channels".red());
+        println!("{}", e);
+        json::error(&format!("{}: {}", "Failed to update channels", e));
+        exit(1);
     }
     println!(
         "{}",
         "Need root access to update system channels".bright_magenta()
     );
-    let _syschannel = Command::new("sudo")
-        .arg("nix-channel")
-        .arg("--update")
-        .status()
-        .expect("Failed to execute process nix-channel");
-    if opts.flake.is_some() {
+    if let Err(e) = updatesyschannel(runner) {
+        println!("{}", "Failed to update system channels".red());
+        println!("{}", e);
+        json::error(&format!("{}: {}", "Failed to update system channels", e));
+        exit(1);
+    }
+    if let Some(flake) = &opts.flake {
         println!("{}", "Updating flake...".green());
-        match updateflake(opts.flake.as_ref().unwrap()) {
-            Ok(()) => {}
-            Err(_) => {
-                println!("{}", "Failed to execute process nix flake".red());
-                exit(1);
-            }
+        if let Err(e) = updateflake(runner, flake) {
+            println!("{}", "Failed to update flake".red());
+            println!("{}", e);
+            json::error(&format!("{}: {}", "Failed to update flake", e));
+            exit(1);
         }
     }
 }
 
-pub fn envinstall_check(opts: NpkgData) -> Result<(), OperateError> {
-    let mut pkgs = vec![];
+fn operate(
+    runner: &dyn CommandRunner,
+    backend: &dyn Backend,
+    mut opts: NpkgData,
+    action: Actions,
+) -> Result<Changes, OperateError> {
+    if opts.output.is_some() {
+        opts.dryrun = true;
+    };
 
-    for p in opts.pkgs {
-        if !opts.currpkgs.contains(&p) {
-            pkgs.push(p);
+    let currpkgs = backend.list(runner)?;
+    let mut changes = Changes::default();
+    for p in &opts.pkgs {
+        // Packages to install may be given as `channel:attr`
+        let attr = match (&action, p.split_once(':')) {
+            (Actions::Install, Some((_, attr))) => attr,
+            _ => p,
+        };
+        let installed = currpkgs.iter().any(|x| x == attr);
+        match action {
+            Actions::Install if installed => changes.skipped.push(p.to_string()),
+            Actions::Remove if !installed => changes.skipped.push(p.to_string()),
+            _ => changes.changed.push(p.to_string()),
         }
     }
+    let pkgs = changes.changed.clone();
 
     if pkgs.is_empty() {
-        println!("No new packages to install");
-        exit(0);
-    }
-
-    match envinstall(pkgs) {
-        Ok(()) => Ok(()),
-        Err(ExecuteError::WriteError(e)) => Err(OperateError::WriteError(e)),
-        Err(ExecuteError::CmdError) => Err(OperateError::CmdError),
+        match action {
+            Actions::Install => println!("No new packages to install"),
+            Actions::Remove => println!("No packages to remove"),
+        }
+        return Ok(changes);
     }
-}
-
-pub fn envremove_check(opts: NpkgData) -> Result<(), OperateError> {
-    let mut pkgs = vec![];
 
-    for p in opts.pkgs {
-        if opts.currpkgs.contains(&p) {
-            pkgs.push(p);
+    let mut edits = match action {
+        Actions::Install => {
+            if let (Some(cfgfile), Some(target)) = (backend.config(), backend.target()) {
+                if !cfgfiles(cfgfile).iter().any(|x| samefile(x, target)) {
+                    println!(
+                        "{} {} {}",
+                        target.yellow(),
+                        "is not imported by".yellow(),
+                        cfgfile.yellow()
+                    );
+                }
+                checkattrs(runner, backend, &opts, &pkgs);
+                checkunfree(&opts, cfgfile, &pkgs);
+            }
+            backend.install(runner, &pkgs)?
         }
+        Actions::Remove => backend.remove(runner, &pkgs)?,
+    };
+    // Imperative backends have changed the installed packages already
+    if edits.is_empty() {
+        return Ok(changes);
     }
 
-    if pkgs.is_empty() {
-        println!("No packages to remove");
-        exit(0);
+    if let Some(output) = &opts.output {
+        if edits.len() > 1 {
+            fail("Packages are declared in more than one file, cannot write to a single output");
+        }
+        for e in &mut edits {
+            e.file = output.to_string();
+        }
     }
-    pkgs = pname_to_name(&pkgs);
 
-    match envremove(pkgs) {
-        Ok(()) => Ok(()),
-        Err(ExecuteError::WriteError(e)) => Err(OperateError::WriteError(e)),
-        Err(ExecuteError::CmdError) => Err(OperateError::CmdError),
+    if !reviewcfg(&opts, &edits) {
+        // Nothing was changed when only the diff was shown
+        changes.skipped.append(&mut changes.changed);
+        return Ok(changes);
     }
-}
-
-fn cfgoperate(mut opts: NpkgData, action: Actions) -> Result<(), OperateError> {
-    match &opts.output {
-        Some(_) => {
-            opts.dryrun = true;
-        }
-        None => {}
-    };
 
-    let f = match opts.pkgmgr {
-        crate::npkgcmd::PackageTypes::Home => {
-            fs::read_to_string(&opts.hmcfg).expect("Failed to read file")
+    if opts.output.is_none() {
+        for e in &edits {
+            backupcfg(backend, &e.file, &e.old)?;
         }
-        crate::npkgcmd::PackageTypes::System => {
-            fs::read_to_string(&opts.syscfg).expect("Failed to read file")
-        }
-        _ => {
-            println!("{}", "Unsupported package type".red());
-            exit(1);
+    }
+    for (i, e) in edits.iter().enumerate() {
+        if let Err(err) = writecfg(runner, &e.file, &e.new, "modify") {
+            for e in &edits[..i] {
+                writecfg(runner, &e.file, &e.old, "restore")?;
+            }
+            return Err(err);
         }
-    };
+    }
 
-    //Add check for current packages
-    let mut pkgs = vec![];
-    for p in &opts.pkgs {
-        match action {
-            Actions::Install => {
-                if !opts.currpkgs.contains(&p) {
-                    pkgs.push(p.to_string());
-                }
-            }
-            Actions::Remove => {
-                if opts.currpkgs.contains(&p) {
-                    pkgs.push(p.to_string());
+    if !opts.dryrun {
+        match cfgswitch(runner, backend) {
+            Ok(()) => {}
+            Err(err) => {
+                println!("{}", "Failed to switch config".red());
+                for e in &edits {
+                    writecfg(runner, &e.file, &e.old, "restore")?;
                 }
+                return Err(err);
             }
         }
     }
+    changes.files = edits.into_iter().map(|e| e.file).collect();
+    Ok(changes)
+}
 
-    if pkgs.is_empty() {
-        println!("No new packages to install");
-        exit(0);
+/// Prints an error and exits
+fn fail(msg: &str) -> ! {
+    println!("{}", msg.red());
+    json::error(msg);
+    exit(1);
+}
+
+/// Whether `a` and `b` point to the same file
+fn samefile(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(x), Ok(y)) => x == y,
+        _ => a == b,
     }
+}
 
-    let query = match opts.pkgmgr {
-        crate::npkgcmd::PackageTypes::Home => "home.packages",
-        crate::npkgcmd::PackageTypes::System => "environment.systemPackages",
-        _ => {
-            println!("{}", "Unsupported package type".red());
-            exit(1);
+/// Tells the user how the configuration is rebuilt
+fn switchmsg(backend: &dyn Backend) {
+    if backend.config().is_none() {
+        return;
+    }
+    if backend.flake().is_some() {
+        println!("Rebuilding with nix flakes");
+    }
+    if backend.root() {
+        println!(
+            "{}",
+            format!("Need root access to rebuild {}", backend.describe()).bright_magenta()
+        );
+    }
+}
+
+/// Activates the configuration of `backend` after it was written
+pub fn cfgswitch(runner: &dyn CommandRunner, backend: &dyn Backend) -> Result<(), OperateError> {
+    switchmsg(backend);
+    backend.apply(runner).map_err(OperateError::from)
+}
+
+impl From<ExecuteError> for OperateError {
+    fn from(e: ExecuteError) -> Self {
+        match e {
+            ExecuteError::CmdError(x) => OperateError::CmdError(x),
+            ExecuteError::WriteError(x) => OperateError::WriteError(x),
+            ExecuteError::EditError(x) => OperateError::EditError(x),
+            ExecuteError::ListError(x) => OperateError::ListError(x),
         }
-    };
+    }
+}
 
-    let out = match action {
-        Actions::Install => match pkwrite(pkgs, &f, Some(query)) {
-            Ok(x) => x,
-            Err(_) => exit(1),
-        },
-        Actions::Remove => match pkrm(pkgs, &f, Some(query)) {
-            Ok(x) => x,
-            Err(_) => exit(1),
-        },
-    };
+/// Atomically replaces `outfile` with `content`, falling back to `sudo` if the file is not writable
+///
+/// `verb` is used in the message shown when root permissions are needed.
+fn writecfg(
+    runner: &dyn CommandRunner,
+    outfile: &str,
+    content: &str,
+    verb: &str,
+) -> Result<(), OperateError> {
+    let mut dir = outfile.split('/').collect::<Vec<&str>>();
+    dir.pop();
+    let dir = dir.join("/");
 
-    let outfile = match opts.output {
-        Some(ref s) => s.to_string(),
-        None => match opts.pkgmgr {
-            crate::npkgcmd::PackageTypes::Home => opts.hmcfg.to_string(),
-            crate::npkgcmd::PackageTypes::System => opts.syscfg.to_string(),
-            _ => {
-                println!("{}", "Unsupported package type".red());
-                exit(1);
-            }
+    if backup::atomicwrite(outfile, content).is_ok() {
+        return Ok(());
+    }
+    if !Path::new(outfile).is_file() {
+        return Err(OperateError::WriteError(dir));
+    }
+
+    println!(
+        "{} {}",
+        format!("Root permissions needed to {}", verb).bright_yellow(),
+        outfile.green()
+    );
+
+    match backup::sudowrite(runner, outfile, content) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(OperateError::WriteError(dir)),
+    }
+}
+
+/// Warns before installing unfree packages into a configuration that does not allow them
+///
+/// The rebuild would fail, so the user has to confirm unless `--yes` was given.
+fn checkunfree(opts: &NpkgData, cfgfile: &str, pkgs: &[String]) {
+    let unfree = unfreepkgs(pkgs);
+    if unfree.is_empty() || allowsunfree(cfgfile) {
+        return;
+    }
+    println!(
+        "{} {} {} {}",
+        "warning:".yellow(),
+        unfree.join(", ").bold(),
+        if unfree.len() == 1 {
+            "is unfree, but"
+        } else {
+            "are unfree, but"
         },
-    };
+        "nixpkgs.config.allowUnfree is not set".yellow()
+    );
+    println!(
+        "Set nixpkgs.config.allowUnfree = true; in {} to allow unfree packages",
+        cfgfile
+    );
+    if !opts.yes && !opts.diffonly && !prompt::confirm("Install unfree packages anyway?") {
+        fail("Aborted");
+    }
+}
 
-    match fs::write(&outfile, &out) {
-        Ok(_) => {}
-        Err(_) => {
-            if Path::new(&outfile).is_file() {
-                println!(
-                    "{} {}",
-                    "Root permissions needed to modify".bright_yellow(),
-                    &outfile.green()
-                );
-
-                let file = outfile.split("/").collect::<Vec<&str>>().pop().unwrap();
-                let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
-                fs::create_dir_all(&cachedir).expect("Failed to create cache directory");
-
-                match fs::write(format!("{}/{}", cachedir, file), &out) {
-                    Ok(_) => {
-                        Command::new("sudo")
-                            .arg("cp")
-                            .arg(format!("{}/{}", cachedir, file))
-                            .arg(&outfile)
-                            .status()
-                            .expect(&format!("{}", "Failed to execute process sudo cp".red()));
-                        fs::remove_file(format!("{}/{}", cachedir, file))
-                            .expect("Failed to remove file");
-                    }
-                    Err(_) => {
-                        let mut dir = outfile.split("/").collect::<Vec<&str>>();
-                        dir.pop();
-                        return Err(OperateError::WriteError(dir.join("/")));
-                    }
-                };
-            } else {
-                let mut dir = outfile.split("/").collect::<Vec<&str>>();
-                dir.pop();
-                return Err(OperateError::WriteError(dir.join("/")));
-            }
-        }
+/// Refuses to install packages that do not exist in nixpkgs, suggesting similar ones instead
+///
+/// Packages are only evaluated if they were not checked before already, see [`NpkgData::unknown`].
+fn checkattrs(
+    runner: &dyn CommandRunner,
+    backend: &dyn Backend,
+    opts: &NpkgData,
+    pkgs: &[String],
+) {
+    let unknown = match &opts.unknown {
+        Some(x) => x.iter().filter(|(x, _)| pkgs.contains(x)).cloned().collect(),
+        None => unknownpkgs(runner, backend.pkgset(runner).as_deref(), pkgs),
     };
+    if !unknown.is_empty() {
+        fail(&unknownmsg(&unknown));
+    }
+}
 
-    if !opts.dryrun {
-        match cfgswitch(&opts) {
-            Ok(()) => {}
-            Err(e) => {
-                println!("{}", "Failed to switch config".red());
-                match fs::write(&outfile, &f) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        if Path::new(&outfile).is_file() {
-                            println!(
-                                "{} {}",
-                                "Root permissions needed to restore".bright_yellow(),
-                                &outfile.green()
-                            );
-
-                            let file = outfile.split("/").collect::<Vec<&str>>().pop().unwrap();
-                            let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
-                            fs::create_dir_all(&cachedir)
-                                .expect("Failed to create cache directory");
-
-                            match fs::write(format!("{}/{}", cachedir, file), &f) {
-                                Ok(_) => {
-                                    Command::new("sudo")
-                                        .arg("cp")
-                                        .arg(format!("{}/{}", cachedir, file))
-                                        .arg(&outfile)
-                                        .status()
-                                        .expect(&format!(
-                                            "{}",
-                                            "Failed to execute process sudo cp".red()
-                                        ));
-                                    fs::remove_file(format!("{}/{}", cachedir, file))
-                                        .expect("Failed to remove file");
-                                }
-                                Err(_) => {
-                                    let mut dir = outfile.split("/").collect::<Vec<&str>>();
-                                    dir.pop();
-                                    return Err(OperateError::WriteError(dir.join("/")));
-                                }
-                            };
-                        } else {
-                            let mut dir = outfile.split("/").collect::<Vec<&str>>();
-                            dir.pop();
-                            return Err(OperateError::WriteError(dir.join("/")));
-                        }
-                    }
-                };
-                return Err(e);
-            }
-        }
+/// Shows the changes about to be made to the configuration files and asks for confirmation
+///
+/// Returns `false` if nothing should be written, either because only the diff was requested or
+/// because the user declined.
+fn reviewcfg(opts: &NpkgData, edits: &[FileEdit]) -> bool {
+    for e in edits {
+        diff::ppdiff(&e.file, &e.old, &e.new);
+    }
+    if opts.diffonly {
+        return false;
     }
-    return Ok(());
+    if !opts.yes && !prompt::confirm("Apply these changes?") {
+        fail("Aborted");
+    }
+    true
 }
 
-pub fn cfgswitch(opts: &NpkgData) -> Result<(), OperateError> {
-    match opts.pkgmgr {
-        crate::npkgcmd::PackageTypes::Home => "home-manager".to_string(),
-        crate::npkgcmd::PackageTypes::System => "nixos-rebuild".to_string(),
-        _ => {
-            println!("{}", "Unsupported package type".red());
-            exit(1);
+/// Saves the current contents of a configuration file before it is modified
+fn backupcfg(backend: &dyn Backend, file: &str, content: &str) -> Result<(), OperateError> {
+    match backup::backup(backend, file, content) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(OperateError::WriteError(
+            backup::backupdir(backend)
+                .to_string_lossy()
+                .to_string(),
+        )),
+    }
+}
+
+/// Restores the `n`th most recent backup of a configuration file and rebuilds
+///
+/// The current configuration is backed up first, so a restore can itself be undone.
+pub fn cfgrestore(
+    runner: &dyn CommandRunner,
+    backend: &dyn Backend,
+    opts: &NpkgData,
+    n: usize,
+) -> Result<(), OperateError> {
+    let cfgfile = match backend.config() {
+        Some(x) => x,
+        None => {
+            fail("Unsupported package type");
         }
     };
 
-    match &opts.flake {
-        None => match &opts.pkgmgr {
-            crate::npkgcmd::PackageTypes::System => {
-                println!("{}", "Need root access to rebuild system".bright_magenta());
-                match systemswitch() {
-                    Ok(()) => Ok(()),
-                    Err(_) => Err(OperateError::CmdError),
-                }
-            }
-            _ => match homeswitch() {
-                Ok(()) => Ok(()),
-                Err(_) => Err(OperateError::CmdError),
-            },
-        },
-        Some(s) => {
-            println!("Rebuilding with nix flakes");
-            match &opts.pkgmgr {
-                crate::npkgcmd::PackageTypes::System => {
-                    println!("{}", "Need root access to rebuild system".bright_magenta());
-                    match systemflakeswitch(s) {
-                        Ok(()) => Ok(()),
-                        Err(_) => Err(OperateError::CmdError),
-                    }
-                }
-                _ => match homeflakeswitch(s) {
-                    Ok(()) => Ok(()),
-                    Err(_) => Err(OperateError::CmdError),
-                },
-            }
+    let backups = backup::backups(backend);
+    let old = match n.checked_sub(1).and_then(|i| backups.get(i)) {
+        Some(x) => x,
+        None => {
+            println!("{} {}", "No backup number".red(), n);
+            exit(1);
         }
+    };
+    // Backups are restored to the file they were taken from, which may be an imported file
+    let cfgfile = match &old.file {
+        Some(x) => x.to_string_lossy().to_string(),
+        None => cfgfile.to_string(),
+    };
+    let content = fs::read_to_string(&old.path)
+        .map_err(|_| OperateError::ReadError(old.path.to_string_lossy().to_string()))?;
+    let f =
+        fs::read_to_string(&cfgfile).map_err(|_| OperateError::ReadError(cfgfile.to_string()))?;
+
+    let edit = FileEdit {
+        file: cfgfile.to_string(),
+        old: f,
+        new: content,
+    };
+    if !reviewcfg(opts, std::slice::from_ref(&edit)) {
+        return Ok(());
+    }
+
+    backupcfg(backend, &cfgfile, &edit.old)?;
+    writecfg(runner, &cfgfile, &edit.new, "restore")?;
+    println!(
+        "{} {} {}",
+        "Restored".green(),
+        cfgfile,
+        format!("from {}", backup::fmttime(old.time)).green()
+    );
+
+    if !opts.dryrun {
+        cfgswitch(runner, backend)?;
     }
+    Ok(())
 }
@@ -0,0 +1,60 @@
+use crate::npkgcmd::npkg;
+use owo_colors::*;
+use std::io::{self, IsTerminal, Write};
+
+/// Asks the user a yes/no question, defaulting to no
+///
+/// Returns `false` without asking if stdin is not a terminal.
+pub fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        println!(
+            "{}",
+            "Not running interactively, pass --yes to confirm".yellow()
+        );
+        return false;
+    }
+    print!("{} [y/N] ", question.bold());
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Whether the user can be asked questions
+pub fn interactive() -> bool {
+    io::stdin().is_terminal()
+}
+
+/// Asks the user to pick any of `count` numbered options, e.g. `1 3 5-7` or `^2`
+///
+/// Returns the picked options counting from 0, asking again until the answer is valid. An empty
+/// answer picks nothing. Returns `None` if stdin is not a terminal or is closed.
+pub fn choose(question: &str, count: usize) -> Option<Vec<usize>> {
+    if !interactive() {
+        return None;
+    }
+    select(question, count)
+}
+
+/// Like [`choose`], but also reads the answer if stdin is not a terminal
+pub fn select(question: &str, count: usize) -> Option<Vec<usize>> {
+    loop {
+        print!("{} [1-{}] ", question.bold(), count);
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        // The answer was not echoed
+        if !interactive() {
+            println!();
+        }
+        match npkg::selection(&answer, count) {
+            Ok(x) => return Some(x),
+            Err(e) => println!("{}", e.to_string().yellow()),
+        }
+    }
+}
@@ -1,46 +1,245 @@
-use crate::npkgcmd::NpkgData;
-use clap::{self, ArgGroup, Parser};
+use crate::npkgcmd::{json, operate::Changes, prompt, search, NpkgData};
+use clap::{self, ArgGroup, CommandFactory, Parser, Subcommand};
 //use npkg::NpkgData;
 use crate::npkgcmd::npkg;
 use crate::npkgcmd::PackageTypes::*;
+use npkg::{Backend, CommandRunner, SystemRunner};
 use owo_colors::*;
 use std::process::exit;
 
+/// Most search results offered when a package to install does not exist
+const PICKLIMIT: usize = 10;
+
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true)]
+#[clap(after_help = "The -i, -r, -l, -s, -f and -u flags of earlier versions still work, e.g. 'npkg -iS hello' is 'npkg install -S hello'.")]
 #[clap(group(
     ArgGroup::new("location")
-        .args(&["system", "home", "env", "search"]),
+        .args(&["system", "home", "env", "profile", "search", "info"]),
 ))]
 #[clap(group(
     ArgGroup::new("action")
-        .args(&["install", "remove", "list", "search", "update"]),
+        .args(&["install", "remove", "list", "search", "find", "info", "update", "restore", "cachestatus"]),
 ))]
 #[clap(group(
     ArgGroup::new("operations")
         .args(&["list", "packages"]),
 ))]
+#[clap(group(
+    ArgGroup::new("query")
+        .args(&["search", "find"]),
+))]
 struct Args {
-    /// Install a package
-    #[clap(short, long)]
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Print machine readable JSON to stdout, other output goes to stderr
+    #[clap(long, global = true)]
+    json: bool,
+
+    /// Download the package list again before running the command, or on its own
+    #[clap(long = "refresh-cache", global = true)]
+    refreshcache: bool,
+
+    // The flags below predate the subcommands and are kept working for existing scripts
+    #[clap(short, long, hide = true)]
     install: bool,
 
-    /// Remove a package
-    #[clap(short, long)]
+    #[clap(short, long, hide = true)]
     remove: bool,
 
-    /// List installed packages
-    #[clap(short, long)]
+    #[clap(short, long, hide = true)]
     list: bool,
 
-    /// Search for a package
-    #[clap(short, long)]
+    #[clap(short, long, hide = true)]
     search: bool,
 
-    /// Update packages
-    #[clap(short, long)]
+    #[clap(short, long, hide = true, requires = "packages")]
+    find: bool,
+
+    #[clap(short, long, hide = true)]
     update: bool,
 
+    #[clap(long, hide = true)]
+    info: bool,
+
+    #[clap(short = 'S', long, hide = true)]
+    system: bool,
+
+    #[clap(short = 'H', long, hide = true)]
+    home: bool,
+
+    #[clap(short = 'E', long, hide = true)]
+    env: bool,
+
+    #[clap(short = 'P', long, hide = true)]
+    profile: bool,
+
+    #[clap(short, long, hide = true, conflicts_with_all = &["system", "home", "profile", "list", "search", "info"])]
+    channel: Option<String>,
+
+    #[clap(short, long, hide = true, value_name = "OPTION", conflicts_with_all = &["env", "profile", "search", "info", "update", "restore"])]
+    attr: Option<String>,
+
+    #[clap(short, long, hide = true, conflicts_with_all = &["list", "search", "info", "env", "profile", "update"])]
+    output: Option<String>,
+
+    #[clap(short, long = "dry-run", hide = true, conflicts_with_all = &["list", "search", "info", "env", "profile", "update"])]
+    dryrun: bool,
+
+    #[clap(short, long, hide = true, conflicts_with_all = &["list", "search", "info", "env", "profile"])]
+    yes: bool,
+
+    #[clap(long = "diff-only", hide = true, conflicts_with_all = &["list", "search", "info", "env", "profile", "update", "yes"])]
+    diffonly: bool,
+
+    #[clap(long, hide = true, value_name = "N", conflicts_with_all = &["output", "env", "profile", "packages"])]
+    restore: Option<Option<usize>>,
+
+    #[clap(long, hide = true, value_name = "N", requires = "query")]
+    limit: Option<usize>,
+
+    #[clap(long = "cache-status", hide = true)]
+    cachestatus: bool,
+
+    #[clap(long = "show-broken", hide = true, requires = "query")]
+    showbroken: bool,
+
+    #[clap(hide = true)]
+    packages: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Install packages
+    #[clap(visible_aliases = &["i", "add"])]
+    Install {
+        #[clap(flatten)]
+        target: Target,
+
+        /// Channel to install nix environment packages from, packages can also be given as 'channel:package'
+        #[clap(short, long, conflicts_with_all = &["system", "home", "profile"])]
+        channel: Option<String>,
+
+        #[clap(flatten)]
+        edit: EditOpts,
+
+        /// Packages to install
+        #[clap(required = true)]
+        packages: Vec<String>,
+    },
+
+    /// Search for packages and pick which of the numbered results to install
+    #[clap(visible_alias = "f")]
+    Find {
+        #[clap(flatten)]
+        target: Target,
+
+        /// Channel to install nix environment packages from
+        #[clap(short, long, conflicts_with_all = &["system", "home", "profile"])]
+        channel: Option<String>,
+
+        #[clap(flatten)]
+        edit: EditOpts,
+
+        #[clap(flatten)]
+        search: SearchOpts,
+
+        /// Search terms
+        #[clap(required = true)]
+        query: Vec<String>,
+    },
+
+    /// Remove packages
+    #[clap(visible_aliases = &["r", "rm", "uninstall"])]
+    Remove {
+        #[clap(flatten)]
+        target: Target,
+
+        #[clap(flatten)]
+        edit: EditOpts,
+
+        /// Packages to remove
+        #[clap(required = true)]
+        packages: Vec<String>,
+    },
+
+    /// List installed packages, from all package sets unless one is given
+    #[clap(visible_aliases = &["l", "ls"])]
+    List {
+        #[clap(flatten)]
+        target: Target,
+
+        /// List option to show instead of 'environment.systemPackages' or 'home.packages'
+        #[clap(short, long, value_name = "OPTION", conflicts_with_all = &["env", "profile"])]
+        attr: Option<String>,
+    },
+
+    /// Search for packages
+    #[clap(visible_alias = "s")]
+    Search {
+        #[clap(flatten)]
+        search: SearchOpts,
+
+        /// Search terms
+        #[clap(required = true)]
+        query: Vec<String>,
+    },
+
+    /// Show all information about packages and where they are installed
+    Info {
+        /// Packages to show
+        #[clap(required = true)]
+        packages: Vec<String>,
+    },
+
+    /// Update packages, in all package sets unless one is given
+    #[clap(visible_aliases = &["u", "upgrade"])]
+    Update {
+        #[clap(flatten)]
+        target: Target,
+    },
+
+    /// Restore backup number N of the configuration file, or list backups if N is not given
+    Restore {
+        /// Use system 'configuration.nix'
+        #[clap(short = 'S', long)]
+        system: bool,
+
+        /// Use home-manager 'home.nix'
+        #[clap(short = 'H', long, conflicts_with = "system")]
+        home: bool,
+
+        /// Do not rebuild, only restore the configuration file
+        #[clap(short, long = "dry-run")]
+        dryrun: bool,
+
+        /// Restore without asking for confirmation
+        #[clap(short, long)]
+        yes: bool,
+
+        /// Only print the changes that would be made to the configuration file
+        #[clap(long = "diff-only", conflicts_with = "yes")]
+        diffonly: bool,
+
+        /// Backup to restore
+        #[clap(value_name = "N")]
+        n: Option<usize>,
+    },
+
+    /// Show which nixpkgs revision the package cache was built from and whether it is up to date
+    #[clap(visible_alias = "cache-status")]
+    Cache,
+}
+
+/// The package set a command works on
+#[derive(clap::Args, Default)]
+#[clap(group(
+    ArgGroup::new("target")
+        .args(&["system", "home", "env", "profile"]),
+))]
+struct Target {
     /// Use system 'configuration.nix'
     #[clap(short = 'S', long)]
     system: bool,
@@ -53,501 +252,1106 @@ struct Args {
     #[clap(short = 'E', long)]
     env: bool,
 
+    /// Use nix profile 'nix profile'
+    #[clap(short = 'P', long)]
+    profile: bool,
+}
+
+/// Options for commands that change installed packages
+#[derive(clap::Args, Default)]
+struct EditOpts {
+    /// List option to manage instead of 'environment.systemPackages' or 'home.packages'
+    #[clap(short, long, value_name = "OPTION", conflicts_with_all = &["env", "profile"])]
+    attr: Option<String>,
+
     /// Output modified configuration file to a specified location
-    #[clap(short, long, conflicts_with_all = &["list", "search", "env", "update"])]
+    #[clap(short, long, conflicts_with_all = &["env", "profile"])]
     output: Option<String>,
 
     /// Do not build any packages, only edit configuration file
-    #[clap(short, long = "dry-run", conflicts_with_all = &["list", "search", "env", "update"])]
+    #[clap(short, long = "dry-run", conflicts_with_all = &["env", "profile"])]
     dryrun: bool,
 
-    /// Packages
-    packages: Vec<String>,
+    /// Apply changes to configuration files without asking for confirmation
+    #[clap(short, long, conflicts_with_all = &["env", "profile"])]
+    yes: bool,
+
+    /// Only print the changes that would be made to the configuration file
+    #[clap(long = "diff-only", conflicts_with_all = &["env", "profile", "yes"])]
+    diffonly: bool,
+}
+
+/// Options for commands that search for packages
+#[derive(clap::Args, Default)]
+struct SearchOpts {
+    /// Only show the N best search results
+    #[clap(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Include packages marked as broken in search results
+    #[clap(long = "show-broken")]
+    showbroken: bool,
+}
+
+/// Parses the command line, also when global flags come before the subcommand
+///
+/// Once a flag is given, clap takes the next word for the packages of the old flags, so
+/// `npkg --json list` is parsed again as `npkg list --json`. Words are left to the old flags when
+/// one of their actions is given, `npkg -s info` still searches for info.
+fn parseargs() -> Args {
+    let argv = std::env::args_os().collect::<Vec<_>>();
+    let args = Args::parse_from(&argv);
+    let action = args.install
+        || args.remove
+        || args.list
+        || args.search
+        || args.find
+        || args.info
+        || args.update
+        || args.restore.is_some()
+        || args.cachestatus;
+    if args.command.is_some() || action {
+        return args;
+    }
+    let mut cmd = Args::command();
+    let name = match args.packages.first() {
+        Some(x) if cmd.find_subcommand(x).is_some() => x,
+        _ => return args,
+    };
+    let i = match argv.iter().position(|x| x == name.as_str()) {
+        Some(x) => x,
+        None => return args,
+    };
+    let global = cmd
+        .get_arguments()
+        .filter(|x| x.is_global_set())
+        .filter_map(|x| x.get_long())
+        .map(|x| format!("--{}", x))
+        .collect::<Vec<_>>();
+    // Options of subcommands have to follow them, `npkg -S install` is not `npkg install -S`
+    if !argv[1..i].iter().all(|x| global.iter().any(|g| x == g.as_str())) {
+        cmd.error(
+            clap::ErrorKind::UnknownArgument,
+            format!("the options before '{}' have to be given after it", name),
+        )
+        .exit();
+    }
+    let mut argv = argv;
+    let x = argv.remove(i);
+    argv.insert(1, x);
+    Args::parse_from(argv)
+}
+
+/// Turns the flags used before subcommands existed into the command they stand for
+fn legacy(args: Args) -> Option<Command> {
+    let target = Target {
+        system: args.system,
+        home: args.home,
+        env: args.env,
+        profile: args.profile,
+    };
+    let edit = EditOpts {
+        attr: args.attr.clone(),
+        output: args.output,
+        dryrun: args.dryrun,
+        yes: args.yes,
+        diffonly: args.diffonly,
+    };
+    let search = SearchOpts {
+        limit: args.limit,
+        showbroken: args.showbroken,
+    };
+    if args.install {
+        Some(Command::Install {
+            target,
+            channel: args.channel,
+            edit,
+            packages: args.packages,
+        })
+    } else if args.find {
+        Some(Command::Find {
+            target,
+            channel: args.channel,
+            edit,
+            search,
+            query: args.packages,
+        })
+    } else if args.remove {
+        Some(Command::Remove {
+            target,
+            edit,
+            packages: args.packages,
+        })
+    } else if args.list {
+        Some(Command::List {
+            target,
+            attr: args.attr,
+        })
+    } else if args.search {
+        Some(Command::Search {
+            search,
+            query: args.packages,
+        })
+    } else if args.info {
+        Some(Command::Info {
+            packages: args.packages,
+        })
+    } else if args.update {
+        Some(Command::Update { target })
+    } else if let Some(n) = args.restore {
+        Some(Command::Restore {
+            system: args.system,
+            home: args.home,
+            dryrun: args.dryrun,
+            yes: args.yes,
+            diffonly: args.diffonly,
+            n,
+        })
+    } else if args.cachestatus {
+        Some(Command::Cache)
+    } else {
+        None
+    }
 }
 
 fn printerror(msg: &str) {
     println!("{} {}", "error:".red(), msg);
+    json::error(msg);
 }
 
-fn pppackages(prepend: &str, packages: &Vec<String>) {
+/// Prints the installed packages of a package set
+///
+/// When the packages of a configuration are spread over several files, the file declaring each
+/// package is shown.
+fn pppackages(prepend: &str, runner: &dyn CommandRunner, backend: &dyn Backend) {
+    let packages = pkfiles(runner, backend);
+    let mut files = packages.iter().filter_map(|(_, f)| f.as_ref()).collect::<Vec<_>>();
+    files.sort();
+    files.dedup();
+
+    // Show imported files relative to the directory of the main file
+    let dir = backend
+        .config()
+        .and_then(|x| std::fs::canonicalize(x).ok())
+        .and_then(|x| x.parent().map(|x| x.to_path_buf()))
+        .unwrap_or_default();
+
     println!("{} {}", prepend.green(), "Packages:".green());
-    for package in packages {
-        println!("  {}", package);
+    for (package, f) in &packages {
+        match f {
+            Some(f) if files.len() > 1 => {
+                let path = std::path::Path::new(f);
+                let f = path.strip_prefix(&dir).unwrap_or(path).display();
+                println!("  {} {}", package, format!("({})", f).bright_black());
+            }
+            _ => println!("  {}", package),
+        }
     }
 }
 
-fn pklst(opts: &NpkgData) -> Vec<String> {
-    match opts.pkgmgr {
-        System => match crate::npkgcmd::parse::syspkgs(opts.syscfg.to_string()) {
-            Ok(mut x) => {
-                x.sort();
-                x
-            }
-            Err(crate::npkgcmd::parse::ParseError::EmptyPkgs) => {
-                printerror("Failed to get system packages");
-                exit(1);
-            }
-        },
-        Home => match crate::npkgcmd::parse::hmpkgs(opts.hmcfg.to_string()) {
-            Ok(mut x) => {
-                x.sort();
-                x
+/// Returns the installed packages of a package set for `--json` output
+fn lstentries(runner: &dyn CommandRunner, backend: &dyn Backend) -> Vec<json::ListEntry> {
+    pkfiles(runner, backend)
+        .into_iter()
+        .map(|(package, file)| json::ListEntry {
+            package,
+            source: backend.source().to_string(),
+            file,
+        })
+        .collect()
+}
+
+fn ppbackups(backend: &dyn Backend) {
+    println!("{} {}", backend.label().green(), "Backups:".green());
+    let backups = crate::npkgcmd::backup::backups(backend);
+    if backups.is_empty() {
+        println!("  {}", "No backups".yellow());
+    }
+    for (i, b) in backups.iter().enumerate() {
+        println!(
+            "  {:>2}  {}  {}",
+            (i + 1).to_string().bold(),
+            crate::npkgcmd::backup::fmttime(b.time),
+            b.file.as_ref().unwrap_or(&b.path).display()
+        );
+    }
+}
+
+fn pkrestore(runner: &dyn CommandRunner, backend: &dyn Backend, opts: &NpkgData, n: usize) {
+    println!(
+        "{} {}",
+        "Restoring configuration for".cyan(),
+        backend.describe().green().bold()
+    );
+    if let Err(e) = crate::npkgcmd::operate::cfgrestore(runner, backend, opts, n) {
+        operatefailed(e, backend, "Could not restore configuration");
+    }
+}
+
+/// Returns `prepend` followed by the list option being managed, if it is not the default
+fn cfglabel(prepend: &str, opts: &NpkgData) -> String {
+    match &opts.attr {
+        Some(x) => format!("{} ({})", prepend, x),
+        None => prepend.to_string(),
+    }
+}
+
+/// Prints the outcome of an install or remove with `--json`
+fn ppchanges(action: &str, backend: &dyn Backend, changes: Changes) {
+    if json::enabled() {
+        json::emit(&json::OpResult {
+            action: action.to_string(),
+            source: backend.source().to_string(),
+            changed: changes.changed,
+            skipped: changes.skipped,
+            files: changes.files,
+            ..Default::default()
+        });
+    }
+}
+
+/// Prints the state of the package cache of one source
+fn ppcachestatus(status: &search::CacheStatus) {
+    let field = |label: &str, value: &str| println!("  {:<10} {}", label.green(), value);
+    println!("{} {}:", "Package cache of".green(), status.name.green().bold());
+    field("Directory", &status.dir);
+    field("Source", &status.source);
+    field("Builder", &status.builder.to_string());
+    field(
+        "Cached",
+        status.cached.as_deref().unwrap_or("no packages cached"),
+    );
+    field(
+        "Current",
+        status.current.as_deref().unwrap_or("could not be evaluated"),
+    );
+    if let Some(x) = status.updated {
+        field("Updated", &crate::npkgcmd::backup::fmttime(x));
+    }
+    if let Some(x) = status.packages {
+        field("Packages", &x.to_string());
+    }
+    if status.uptodate {
+        field("Status", &"up to date".green().to_string());
+    } else {
+        field("Status", &"out of date, run 'npkg --refresh-cache'".yellow().to_string());
+    }
+}
+
+/// Prints a card with all metadata of a package and where it is installed
+fn ppinfo(info: &npkg::PkgInfo, installed: &[json::ListEntry]) {
+    let field = |label: &str, value: &str| println!("  {:<12} {}", label.green(), value);
+
+    println!("{} ({})", info.attr.bold(), info.version);
+    if let Some(x) = &info.description {
+        println!("  {}", x.trim());
+    }
+    if let Some(x) = &info.long_description {
+        println!();
+        for line in x.trim().lines() {
+            println!("  {}", line);
+        }
+    }
+    println!();
+
+    field("Name", &info.name);
+    if !info.homepage.is_empty() {
+        field("Homepage", &info.homepage.join(", "));
+    }
+    if !info.licenses.is_empty() {
+        let licenses = info
+            .licenses
+            .iter()
+            .map(|x| {
+                let id = x.spdx_id.as_ref().or(x.short_name.as_ref());
+                match (id, &x.full_name) {
+                    (Some(id), Some(full)) => format!("{} ({})", id, full),
+                    (Some(id), None) => id.to_string(),
+                    (None, Some(full)) => full.to_string(),
+                    (None, None) => "unknown".to_string(),
+                }
+            })
+            .collect::<Vec<_>>();
+        field("License", &licenses.join(", "));
+    }
+    if !info.maintainers.is_empty() {
+        let maintainers = info
+            .maintainers
+            .iter()
+            .map(|x| match (&x.name, &x.github) {
+                (Some(name), Some(gh)) => format!("{} (@{})", name, gh),
+                (Some(name), None) => name.to_string(),
+                (None, Some(gh)) => format!("@{}", gh),
+                (None, None) => x.email.clone().unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        field("Maintainers", &maintainers.join(", "));
+    }
+    if !info.platforms.is_empty() {
+        field("Platforms", &info.platforms.join(", "));
+    }
+    if !info.outputs.is_empty() {
+        field("Outputs", &info.outputs.join(", "));
+    }
+    if let Some(x) = &info.position {
+        field("Position", x);
+    }
+
+    let mut status = vec![];
+    if info.broken {
+        status.push("broken".red().to_string());
+    }
+    if info.insecure {
+        status.push("insecure".red().to_string());
+    }
+    if info.unfree {
+        status.push("unfree".yellow().to_string());
+    }
+    if info.unsupported {
+        status.push("unsupported".yellow().to_string());
+    }
+    if !status.is_empty() {
+        field("Status", &status.join(", "));
+    }
+    for x in &info.vulnerabilities {
+        field("Vulnerable", x);
+    }
+
+    let installed = installed
+        .iter()
+        .map(|x| match &x.file {
+            Some(f) => format!("{} ({})", x.source, f),
+            None => x.source.to_string(),
+        })
+        .collect::<Vec<_>>();
+    if installed.is_empty() {
+        field("Installed", &"no".bright_black().to_string());
+    } else {
+        field("Installed", &installed.join(", "));
+    }
+}
+
+fn pklst(runner: &dyn CommandRunner, opts: &NpkgData) -> Vec<String> {
+    let backend = crate::npkgcmd::operate::backend(opts);
+    match backend.list(runner) {
+        Ok(x) => x,
+        Err(_) => {
+            printerror(&format!("Failed to get {} packages", backend.describe()));
+            exit(1);
+        }
+    }
+}
+
+/// Lists the installed packages of `backend` with the file declaring each, exiting on failure
+fn pkfiles(runner: &dyn CommandRunner, backend: &dyn Backend) -> Vec<(String, Option<String>)> {
+    match backend.files(runner) {
+        Ok(x) => x,
+        Err(_) => {
+            printerror(&format!("Failed to get {} packages", backend.describe()));
+            exit(1);
+        }
+    }
+}
+
+/// Replaces packages in `pkgs` that are not attributes of the package set of `backend` with
+/// search results the user picks
+///
+/// Returns the packages to install and the packages that do not exist, each with suggestions.
+/// Exits listing the packages that do not exist if nothing was found for them, or if stdin is
+/// not a terminal, with the search results as suggestions.
+fn pickpkgs(
+    runner: &dyn CommandRunner,
+    backend: &dyn Backend,
+    pkgs: Vec<String>,
+) -> (Vec<String>, Vec<(String, Vec<String>)>) {
+    let mut unknown = search::unknownpkgs(runner, backend.pkgset(runner).as_deref(), &pkgs);
+    let mut picked = vec![];
+    let mut unpicked = false;
+    for pkg in pkgs {
+        if !unknown.iter().any(|(x, _)| x == &pkg) {
+            picked.push(pkg);
+            continue;
+        }
+        // Search results come from the main source, `channel:attr` names a package of another
+        let found = match pkg.contains(':') {
+            true => None,
+            false => search::candidates(runner, &pkg, PICKLIMIT),
+        };
+        let found = match found {
+            Some(x) => x,
+            None => {
+                unpicked = true;
+                continue;
             }
-            Err(crate::npkgcmd::parse::ParseError::EmptyPkgs) => {
-                printerror("Failed to get home-manager packages");
-                exit(1);
+        };
+        if !prompt::interactive() {
+            // The search results are better suggestions than similar attributes
+            if let Some(x) = unknown.iter_mut().find(|(x, _)| x == &pkg) {
+                x.1 = found.iter().map(|x| x.pkg.pname.to_string()).collect();
             }
-        },
-        Env => match crate::npkgcmd::parse::envpkgs() {
-            Ok(mut x) => {
-                x.sort();
-                x
+            unpicked = true;
+            continue;
+        }
+        unknown.retain(|(x, _)| x != &pkg);
+
+        println!("{} {}", "No package named".yellow(), pkg.bold());
+        for (i, search::Found { pkg, .. }) in found.iter().enumerate() {
+            println!(
+                "{:>3} {} ({})",
+                (i + 1).to_string().magenta(),
+                pkg.pname.bold(),
+                pkg.version
+            );
+            if let Some(x) = &pkg.description {
+                println!("    {}", x.trim().replace('\n', " "));
             }
-            Err(crate::npkgcmd::parse::ParseError::EmptyPkgs) => {
-                printerror("Failed to get nix environment packages");
+        }
+        match prompt::choose("Packages to install", found.len()) {
+            Some(x) if x.is_empty() => println!("Skipping {}", pkg),
+            Some(x) => picked.extend(x.into_iter().map(|i| found[i].pkg.pname.to_string())),
+            None => {
+                printerror("Aborted");
                 exit(1);
             }
-        },
+        }
+    }
+    if unpicked {
+        printerror(&search::unknownmsg(&unknown));
+        exit(1);
     }
+    (picked, unknown)
 }
 
-fn pkinstall(mut opts: NpkgData) {
-    match opts.pkgmgr {
-        System => {
-            opts.currpkgs = pklst(&opts);
-            match crate::npkgcmd::operate::pkinstall(opts) {
-                Ok(()) => {}
-                Err(crate::npkgcmd::operate::OperateError::CmdError) => {
-                    printerror("Could not rebuild configuration");
-                    exit(1);
-                }
-                Err(crate::npkgcmd::operate::OperateError::WriteError(f)) => {
-                    printerror(format!("Could not write to configuration file, do you have permissions in the directory \"{}\"?", f).as_str());
-                    exit(1);
-                }
-            }
+/// Packages installed in each place, used to mark search results
+struct Installed {
+    system: Vec<String>,
+    home: Vec<String>,
+    env: Vec<String>,
+}
+
+/// Lists the packages installed in the system, home-manager and the user environment
+///
+/// Leaves `opts.pkgmgr` set to `envmgr`.
+fn installedpkgs(
+    runner: &dyn CommandRunner,
+    opts: &mut NpkgData,
+    hm: bool,
+    envmgr: crate::npkgcmd::PackageTypes,
+) -> Installed {
+    opts.pkgmgr = System;
+    let system = pklst(runner, opts);
+    opts.pkgmgr = Home;
+    let home = if hm { pklst(runner, opts) } else { Vec::new() };
+    opts.pkgmgr = envmgr;
+    let env = pklst(runner, opts);
+    Installed { system, home, env }
+}
+
+/// Searches for packages, exiting if the search fails
+fn searchpkgs(
+    runner: &dyn CommandRunner,
+    query: &[String],
+    showbroken: bool,
+    limit: Option<usize>,
+) -> Vec<search::Found> {
+    let mut pkgdata = match search::search(runner, query) {
+        Ok(x) => x,
+        Err(e) => {
+            printerror(&format!("Could not search for packages: {}", e));
+            exit(1);
         }
-        Home => {
-            opts.currpkgs = pklst(&opts);
-            match crate::npkgcmd::operate::pkinstall(opts) {
-                Ok(()) => {}
-                Err(crate::npkgcmd::operate::OperateError::CmdError) => {
-                    printerror("Could not rebuild configuration");
-                    exit(1);
-                }
-                Err(crate::npkgcmd::operate::OperateError::WriteError(f)) => {
-                    printerror(format!("Could not write to configuration file, does the directory \"{}\" exist?", f).as_str());
-                    exit(1);
-                }
-            }
+    };
+    if !showbroken {
+        pkgdata.retain(|x| !x.pkg.broken);
+    }
+    if let Some(limit) = limit {
+        pkgdata.truncate(limit);
+    }
+    pkgdata
+}
+
+/// Prints search results, marking where they are installed
+///
+/// If `numbered` is set, results are numbered from 1 so they can be picked with [`prompt::select`].
+fn ppfound(
+    found: &[search::Found],
+    query: &[String],
+    installed: &Installed,
+    pkgmgr: &crate::npkgcmd::PackageTypes,
+    numbered: bool,
+) {
+    // Only plain terms are highlighted, filters may match other fields
+    let terms = npkg::Query::parse(query)
+        .map(|x| x.terms)
+        .unwrap_or_default();
+    // Sources are only worth showing if the results come from more than one
+    let multisource = found
+        .iter()
+        .any(|x| x.sources.iter().any(|(s, _)| s != &found[0].sources[0].0));
+    for (num, search::Found { pkg, sources }) in found.iter().enumerate() {
+        let name = highlight(&pkg.pname, &terms);
+        let mut outstr = match numbered {
+            true => format!(
+                "{:>3} {} ({})",
+                (num + 1).to_string().magenta(),
+                name.bold(),
+                pkg.version
+            ),
+            false => format!("* {} ({})", name.bold(), pkg.version),
+        };
+        if installed.system.contains(&pkg.pname) {
+            outstr += &format!(" ({})", "system".bright_red());
         }
-        Env => {
-            opts.currpkgs = pklst(&opts);
-            match crate::npkgcmd::operate::envinstall_check(opts) {
-                Ok(()) => {}
-                Err(crate::npkgcmd::operate::OperateError::CmdError) => {
-                    printerror("Could not install packages");
-                    exit(1);
-                }
-                Err(crate::npkgcmd::operate::OperateError::WriteError(_)) => {
-                    printerror("Could not write file");
-                    exit(1);
-                }
-            }
+        if installed.home.contains(&pkg.pname) {
+            outstr += &format!(" ({})", "home".bright_cyan());
+        }
+        if installed.env.contains(&pkg.pname) {
+            let marker = match pkgmgr {
+                Profile => "nix profile",
+                _ => "nix env",
+            };
+            outstr += &format!(" ({})", marker.bright_yellow());
+        }
+        if pkg.broken {
+            outstr += &format!(" [{}]", "broken".red());
+        }
+        if pkg.insecure {
+            outstr += &format!(" [{}]", "insecure".red());
+        }
+        if pkg.unfree {
+            outstr += &format!(" [{}]", "unfree".yellow());
+        }
+        if pkg.unsupported {
+            outstr += &format!(" [{}]", "unsupported".yellow());
+        }
+        println!("{}", outstr);
+        if multisource {
+            let available = sources
+                .iter()
+                .map(|(source, version)| {
+                    if version == &pkg.version {
+                        format!("{} ({})", source.blue(), version)
+                    } else {
+                        format!("{} ({})", source.blue(), version.green())
+                    }
+                })
+                .collect::<Vec<_>>();
+            println!("  available in {}", available.join(", "));
+        }
+
+        if let Some(x) = &pkg.description {
+            let desc = highlight(&x.trim().replace("\n", " "), &terms);
+            println!("  {}", desc)
         }
+        println!();
     }
 }
 
-fn pkremove(mut opts: NpkgData) {
-    match opts.pkgmgr {
-        System => {
-            opts.currpkgs = pklst(&opts);
-            match crate::npkgcmd::operate::pkremove(opts) {
-                Ok(()) => {}
-                Err(crate::npkgcmd::operate::OperateError::CmdError) => {
-                    printerror("Could not rebuild configuration");
-                    exit(1);
-                }
-                Err(crate::npkgcmd::operate::OperateError::WriteError(f)) => {
-                    printerror(format!("Could not write to configuration file, does the directory \"{}\" exist?", f).as_str());
-                    exit(1);
-                }
-            }
+/// Highlights every occurrence of one of `terms` in `text`, ignoring case
+fn highlight(text: &str, terms: &[String]) -> String {
+    let lower = text.to_lowercase();
+    let mut ranges = vec![];
+    for term in terms.iter().map(|x| x.to_lowercase()).filter(|x| !x.is_empty()) {
+        ranges.extend(lower.match_indices(&term).map(|(i, x)| (i, i + x.len())));
+    }
+    ranges.sort();
+    let mut out = String::new();
+    let mut end = 0;
+    for (start, stop) in ranges {
+        // Lowercasing may change the length of some characters, so matches may not line up
+        if stop <= end || !text.is_char_boundary(start) || !text.is_char_boundary(stop) {
+            continue;
         }
-        Home => {
-            opts.currpkgs = pklst(&opts);
-            match crate::npkgcmd::operate::pkremove(opts) {
-                Ok(()) => {}
-                Err(crate::npkgcmd::operate::OperateError::CmdError) => {
-                    printerror("Could not rebuild configuration");
-                    exit(1);
-                }
-                Err(crate::npkgcmd::operate::OperateError::WriteError(f)) => {
-                    printerror(format!("Could not write to configuration file, does the directory \"{}\" exist?", f).as_str());
-                    exit(1);
-                }
+        // Overlapping matches are merged
+        let start = start.max(end);
+        out += &text[end..start];
+        out += &(&text[start..stop]).green().to_string();
+        end = stop;
+    }
+    out += &text[end..];
+    out
+}
+
+/// Searches for `query` and asks which of the numbered results to install
+///
+/// The answer is read from stdin even if it is not a terminal, so selections can be piped in.
+fn findpkgs(
+    runner: &dyn CommandRunner,
+    query: &[String],
+    showbroken: bool,
+    limit: Option<usize>,
+    installed: &Installed,
+    pkgmgr: &crate::npkgcmd::PackageTypes,
+) -> Vec<String> {
+    let found = searchpkgs(runner, query, showbroken, limit);
+    if found.is_empty() {
+        printerror(&format!("No packages found for '{}'", query.join(" ")));
+        exit(1);
+    }
+    ppfound(&found, query, installed, pkgmgr, true);
+    match prompt::select("Packages to install (e.g. 1 2 5-7, ^4 to exclude)", found.len()) {
+        Some(x) if x.is_empty() => {
+            println!("Nothing to install");
+            if json::enabled() {
+                json::emit(&json::OpResult {
+                    action: "install".to_string(),
+                    ..Default::default()
+                });
             }
+            exit(0);
         }
-        Env => {
-            opts.currpkgs = pklst(&opts);
-            match crate::npkgcmd::operate::envremove_check(opts) {
-                Ok(()) => {}
-                Err(crate::npkgcmd::operate::OperateError::CmdError) => {
-                    printerror("Could not remove packages");
-                    exit(1);
-                }
-                Err(crate::npkgcmd::operate::OperateError::WriteError(_)) => {
-                    printerror("Could not write file");
-                    exit(1);
-                }
-            }
+        Some(x) => x.into_iter().map(|i| found[i].pkg.pname.to_string()).collect(),
+        None => {
+            printerror("Aborted");
+            exit(1);
         }
     }
 }
 
-fn pkupdate(opts: &NpkgData) {
-    match opts.pkgmgr {
-        Home => {
-            match crate::npkgcmd::operate::cfgswitch(&opts) {
-                Ok(()) => {}
-                Err(crate::npkgcmd::operate::OperateError::CmdError) => {
-                    printerror("Could not rebuild configuration");
-                    exit(1);
-                }
-                Err(crate::npkgcmd::operate::OperateError::WriteError(f)) => {
-                    printerror(format!("Could not write to configuration file, does the directory \"{}\" exist?", f).as_str());
-                    exit(1);
-                }
+fn pkinstall(runner: &dyn CommandRunner, backend: &dyn Backend, opts: NpkgData) {
+    println!(
+        "{} {}",
+        "Installing package to".cyan(),
+        backend.describe().green().bold()
+    );
+    match crate::npkgcmd::operate::pkinstall(runner, backend, opts) {
+        Ok(x) => ppchanges("install", backend, x),
+        Err(e) => operatefailed(e, backend, "Could not install packages"),
+    }
+}
+
+fn pkremove(runner: &dyn CommandRunner, backend: &dyn Backend, opts: NpkgData) {
+    println!(
+        "{} {}",
+        "Removing package from".cyan(),
+        backend.describe().green().bold()
+    );
+    match crate::npkgcmd::operate::pkremove(runner, backend, opts) {
+        Ok(x) => ppchanges("remove", backend, x),
+        Err(e) => operatefailed(e, backend, "Could not remove packages"),
+    }
+}
+
+fn pkupdate(runner: &dyn CommandRunner, backend: &dyn Backend) {
+    println!(
+        "{} {}",
+        "Updating packages in".cyan(),
+        backend.describe().green().bold()
+    );
+    if let Err(e) = crate::npkgcmd::operate::pkupdate(runner, backend) {
+        operatefailed(e, backend, "Could not update packages");
+    }
+}
+
+/// Prints why changing packages failed and exits
+///
+/// `failed` describes a failed `nix-env` or `nix profile` command, configurations fail to rebuild instead.
+fn operatefailed(e: crate::npkgcmd::operate::OperateError, backend: &dyn Backend, failed: &str) -> ! {
+    use crate::npkgcmd::operate::OperateError;
+    match e {
+        OperateError::CmdError(e) => {
+            match backend.config() {
+                Some(_) => printerror("Could not rebuild configuration"),
+                None => printerror(failed),
             }
+            println!("{}", e);
         }
-        System => {
-            match crate::npkgcmd::operate::cfgswitch(&opts) {
-                Ok(()) => {}
-                Err(crate::npkgcmd::operate::OperateError::CmdError) => {
-                    printerror("Could not rebuild configuration");
-                    exit(1);
-                }
-                Err(crate::npkgcmd::operate::OperateError::WriteError(f)) => {
-                    printerror(format!("Could not write to configuration file, does the directory \"{}\" exist?", f).as_str());
-                    exit(1);
-                }
-            };
+        OperateError::WriteError(f) => match backend.config() {
+            Some(_) => printerror(format!("Could not write to configuration file, do you have permissions in the directory \"{}\"?", f).as_str()),
+            None => printerror("Could not write file"),
+        },
+        OperateError::EditError(e) => {
+            printerror(format!("Could not edit configuration file: {}", e).as_str());
         }
-        Env => {
-            match npkg::envupdate() {
-                Ok(()) => {}
-                Err(npkg::ExecuteError::CmdError) => {
-                    printerror("Could not update packages");
-                    exit(1);
-                }
-                Err(npkg::ExecuteError::WriteError(_)) => {
-                    printerror("Could not write file");
-                    exit(1);
-                }
-            };
+        OperateError::ListError(x) => {
+            printerror(format!("Could not read packages from {}", x).as_str());
+        }
+        OperateError::ReadError(x) => {
+            printerror(format!("Could not read {}", x).as_str());
         }
     }
+    exit(1);
+}
+
+/// Returns the package set `target` selects, or `default` if none is given
+///
+/// Exits if home-manager is selected but not installed.
+fn location(
+    target: &Target,
+    default: crate::npkgcmd::PackageTypes,
+    hm: bool,
+) -> crate::npkgcmd::PackageTypes {
+    if target.home {
+        if !hm {
+            printerror("home-manager is not installed");
+            exit(1);
+        }
+        Home
+    } else if target.system {
+        System
+    } else {
+        default
+    }
+}
+
+/// Copies the options of commands that change installed packages to `opts`
+///
+/// Exits if a list option is given for a package set without one.
+fn editopts(opts: &mut NpkgData, edit: EditOpts, target: &Target) {
+    if edit.attr.is_some() && !(target.system || target.home) {
+        printerror("--attr can only be used with --system or --home");
+        exit(1);
+    }
+    opts.dryrun = edit.dryrun || edit.output.is_some();
+    opts.yes = edit.yes;
+    opts.diffonly = edit.diffonly;
+    opts.output = edit.output;
+    opts.attr = edit.attr;
 }
 
 pub fn main() {
-    let args = Args::parse();
-
-    let hm = match std::process::Command::new("home-manager")
-        .arg("--help")
-        .output()
-    {
-        Ok(x) => {
-            if x.status.success() {
-                true
-            } else {
-                false
+    let mut args = parseargs();
+
+    let runner = &SystemRunner;
+
+    if args.json {
+        json::enable();
+    }
+
+    let hm = runner
+        .output(std::process::Command::new("home-manager").arg("--help"))
+        .is_ok();
+
+    let cfgdir = crate::npkgcmd::config::checkconfig();
+    let config = crate::npkgcmd::config::readconfig(cfgdir);
+
+    let refreshcache = args.refreshcache;
+    let command = match args.command.take() {
+        Some(x) => Some(x),
+        None => legacy(args),
+    };
+
+    if refreshcache {
+        if let Err(e) = crate::npkgcmd::search::refreshcache(runner) {
+            printerror(&format!("Could not refresh the package cache: {}", e));
+            exit(1);
+        }
+        if command.is_none() {
+            if json::enabled() {
+                json::emit(&json::OpResult {
+                    action: "refresh-cache".to_string(),
+                    ..Default::default()
+                });
             }
+            exit(0);
+        }
+    }
+
+    let command = match command {
+        Some(x) => x,
+        None => {
+            printerror("no operation specified");
+            println!("Try 'npkg --help' for more information.");
+            exit(1);
         }
-        Err(_) => false,
     };
 
-    let cfgdir = crate::npkgcmd::config::checkconfig();
-    let (syscfg, hmcfg, flake) = crate::npkgcmd::config::readconfig(cfgdir);
+    // The user environment is managed either by nix-env or nix profile
+    let envmgr = |target: &Target| {
+        if target.profile || (config.nixprofile && !target.env) {
+            Profile
+        } else {
+            Env
+        }
+    };
 
     let mut opts = NpkgData {
         pkgmgr: Env,
-        pkgs: args.packages,
-        dryrun: args.dryrun || args.output.is_some(),
-        output: args.output,
-        syscfg: syscfg,
-        hmcfg: hmcfg,
-        flake: flake,
-        currpkgs: vec![],
+        pkgs: vec![],
+        dryrun: false,
+        yes: false,
+        diffonly: false,
+        output: None,
+        systarget: config
+            .systemtarget
+            .clone()
+            .unwrap_or_else(|| config.systemconfig.clone()),
+        hmtarget: config
+            .hometarget
+            .clone()
+            .unwrap_or_else(|| config.homeconfig.clone()),
+        syscfg: config.systemconfig.clone(),
+        hmcfg: config.homeconfig.clone(),
+        flake: config.flake.clone(),
+        channel: config.channel.clone(),
+        attr: None,
+        unknown: None,
     };
 
-    if args.install {
-        if args.home {
-            if !hm {
-                printerror("home-manager is not installed");
-                exit(1);
-            }
-            opts.pkgmgr = Home;
-            println!(
-                "{} {}",
-                "Installing package to".cyan(),
-                "home".green().bold()
-            );
-            pkinstall(opts);
-        } else if args.system {
-            opts.pkgmgr = System;
-            println!(
-                "{} {}",
-                "Installing package to".cyan(),
-                "system".green().bold()
-            );
-            pkinstall(opts);
-        } else {
-            //Default env
-            println!(
-                "{} {}",
-                "Installing package to".cyan(),
-                "nix environment".green().bold()
-            );
-            pkinstall(opts);
+    match command {
+        Command::Install {
+            target,
+            channel,
+            edit,
+            packages,
+        } => {
+            editopts(&mut opts, edit, &target);
+            opts.channel = channel.or(opts.channel);
+            opts.pkgmgr = location(&target, envmgr(&target), hm);
+            let backend = crate::npkgcmd::operate::backend(&opts);
+            let (pkgs, unknown) = pickpkgs(runner, backend.as_ref(), packages);
+            opts.pkgs = pkgs;
+            opts.unknown = Some(unknown);
+            pkinstall(runner, backend.as_ref(), opts);
         }
-    } else if args.remove {
-        if args.home {
-            opts.pkgmgr = Home;
-            if !hm {
-                printerror("home-manager is not installed");
-                exit(1);
-            }
-            println!(
-                "{} {}",
-                "Removing package from".cyan(),
-                "home".green().bold()
-            );
-            pkremove(opts);
-        } else if args.system {
-            opts.pkgmgr = System;
-            println!(
-                "{} {}",
-                "Removing package from".cyan(),
-                "system".green().bold()
-            );
-            pkremove(opts);
-        } else {
-            //Default env
-            opts.pkgmgr = Env;
-            println!(
-                "{} {}",
-                "Removing package from".cyan(),
-                "nix environment".green().bold()
+        Command::Find {
+            target,
+            channel,
+            edit,
+            search,
+            query,
+        } => {
+            editopts(&mut opts, edit, &target);
+            opts.channel = channel.or(opts.channel);
+            let pkgmgr = location(&target, envmgr(&target), hm);
+            let installed = installedpkgs(runner, &mut opts, hm, envmgr(&target));
+            opts.pkgs = findpkgs(
+                runner,
+                &query,
+                search.showbroken,
+                search.limit,
+                &installed,
+                &envmgr(&target),
             );
-            pkremove(opts);
+            opts.pkgmgr = pkgmgr;
+            let backend = crate::npkgcmd::operate::backend(&opts);
+            pkinstall(runner, backend.as_ref(), opts);
         }
-    } else if args.list {
-        if args.home {
-            //Add check for current packages
-            if !hm {
-                printerror("home-manager is not installed");
-                exit(1);
+        Command::Remove {
+            target,
+            edit,
+            packages,
+        } => {
+            editopts(&mut opts, edit, &target);
+            opts.pkgs = packages;
+            opts.pkgmgr = location(&target, envmgr(&target), hm);
+            let backend = crate::npkgcmd::operate::backend(&opts);
+            pkremove(runner, backend.as_ref(), opts);
+        }
+        Command::List { target, attr } => {
+            opts.attr = attr;
+            let sources = if target.home || target.system || target.env || target.profile {
+                vec![location(&target, envmgr(&target), hm)]
+            } else {
+                //Default to all packages
+                let mut x = vec![System];
+                if hm {
+                    x.push(Home);
+                }
+                // The nix environment has no list options
+                if opts.attr.is_none() {
+                    x.push(envmgr(&target));
+                }
+                x
+            };
+
+            let mut entries = vec![];
+            for source in sources {
+                opts.pkgmgr = source;
+                let backend = crate::npkgcmd::operate::backend(&opts);
+                if json::enabled() {
+                    entries.append(&mut lstentries(runner, backend.as_ref()));
+                    continue;
+                }
+                let label = match backend.config() {
+                    Some(_) => cfglabel(backend.label(), &opts),
+                    None => backend.label().to_string(),
+                };
+                pppackages(&label, runner, backend.as_ref());
             }
-            opts.pkgmgr = Home;
-            let currpkgs = pklst(&opts);
-            pppackages("Home Manager", &currpkgs);
-        } else if args.system {
-            opts.pkgmgr = System;
-            let currpkgs = pklst(&opts);
-            pppackages("System", &currpkgs);
-        } else if args.env {
-            opts.pkgmgr = Env;
-            let currpkgs = pklst(&opts);
-            pppackages("Nix Environment", &currpkgs);
-        } else {
-            //Default to all packages
-            opts.pkgmgr = System;
-            let syslst = pklst(&opts);
-            opts.pkgmgr = Home;
-            let homelst = if hm { pklst(&opts) } else { Vec::new() };
-            opts.pkgmgr = Env;
-            let envlst = pklst(&opts);
-            pppackages("System", &syslst);
-            if hm {
-                pppackages("Home Manager", &homelst)
+            if json::enabled() {
+                json::emit(&entries);
             }
-            pppackages("Nix Environment", &envlst);
         }
-    } else if args.search {
-        // Get packages
-        opts.pkgmgr = System;
-        let syslst = pklst(&opts);
-        opts.pkgmgr = Home;
-        let homelst = if hm { pklst(&opts) } else { Vec::new() };
-        opts.pkgmgr = Env;
-        let envlst = pklst(&opts);
-
-        // Search for packages
-        let pkgdata = match crate::npkgcmd::search::search(&opts.pkgs) {
-            Ok(x) => x,
-            Err(_) => {
-                printerror("Could not search for packages");
-                exit(1);
-            }
-        };
-
-        for pkg in pkgdata {
-            let mut name = pkg.pname.to_string();
+        Command::Search { search, query } => {
+            let envmgr = envmgr(&Target::default());
+            let installed = installedpkgs(runner, &mut opts, hm, envmgr);
+            let pkgdata = searchpkgs(runner, &query, search.showbroken, search.limit);
 
-            let mut idx = vec![];
-            let nl = name.to_lowercase();
-            for st in &opts.pkgs {
-                let sl = st.to_lowercase();
-                let mut y = nl.match_indices(&sl).collect::<Vec<_>>();
-                idx.append(&mut y);
-            }
-            idx.sort();
-            let mut idx2: Vec<(usize, &str)> = vec![];
-            for i in idx {
-                if {
-                    let mut b = true;
-                    idx2.retain(|j| {
-                        if j.0 == i.0 {
-                            if i.1.len() > j.1.len() {
-                                return false;
-                            } else {
-                                b = false;
-                                return true;
-                            }
+            if json::enabled() {
+                let envsource = crate::npkgcmd::operate::backend(&opts).source().to_string();
+                let entries = pkgdata
+                    .into_iter()
+                    .map(|search::Found { pkg, sources }| {
+                        let mut installed_in = vec![];
+                        if installed.system.contains(&pkg.pname) {
+                            installed_in.push("system".to_string());
                         }
-                        return true;
-                    });
-                    b
-                } {
-                    idx2.push(i);
-                }
+                        if installed.home.contains(&pkg.pname) {
+                            installed_in.push("home".to_string());
+                        }
+                        if installed.env.contains(&pkg.pname) {
+                            installed_in.push(envsource.to_string());
+                        }
+                        json::SearchEntry {
+                            pname: pkg.pname,
+                            version: pkg.version,
+                            description: pkg.description,
+                            score: pkg.score,
+                            installed: installed_in,
+                            broken: pkg.broken,
+                            insecure: pkg.insecure,
+                            unfree: pkg.unfree,
+                            unsupported: pkg.unsupported,
+                            sources: sources
+                                .into_iter()
+                                .map(|(source, version)| json::SourceVersion { source, version })
+                                .collect(),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                json::emit(&entries);
+                exit(0);
             }
-            let mut offset = 0;
 
-            for (i, st) in idx2 {
-                let j = i + offset;
-                let n = name[j..j + st.len()].to_string();
-                name.replace_range(j..j + st.len(), &n.green().to_string());
-                offset += 10;
+            ppfound(&pkgdata, &query, &installed, &envmgr, false);
+        }
+        Command::Update { target } => {
+            let envmgr = envmgr(&target);
+            let sources = if target.home || target.system || target.env || target.profile {
+                vec![location(&target, envmgr, hm)]
+            } else {
+                let mut x = vec![System];
+                if hm {
+                    x.push(Home);
+                }
+                x.push(envmgr);
+                x
+            };
+            // nix profile installs from flakes, so there are no channels to update
+            if !matches!(sources[..], [Profile]) {
+                crate::npkgcmd::operate::chnupdate(runner, &opts);
             }
-            let mut outstr = format!("* {} ({})", name.bold(), pkg.version);
-            if syslst.contains(&pkg.pname) {
-                outstr += &format!(" ({})", "system".bright_red());
+            let mut updated = vec![];
+            for source in &sources {
+                opts.pkgmgr = *source;
+                let backend = crate::npkgcmd::operate::backend(&opts);
+                pkupdate(runner, backend.as_ref());
+                updated.push(backend.source().to_string());
             }
-            if homelst.contains(&pkg.pname) {
-                outstr += &format!(" ({})", "home".bright_cyan());
+            if json::enabled() {
+                let source = match &updated[..] {
+                    [x] => x,
+                    _ => "all",
+                };
+                json::emit(&json::OpResult {
+                    action: "update".to_string(),
+                    source: source.to_string(),
+                    ..Default::default()
+                });
             }
-            if envlst.contains(&pkg.pname) {
-                outstr += &format!(" ({})", "nix env".bright_yellow());
+        }
+        Command::Info { packages } => {
+            if packages.is_empty() {
+                printerror("no packages specified");
+                exit(1);
             }
-            println!("{}", outstr);
-
-            match pkg.description {
-                Some(x) => {
-                    let mut desc = x.trim().replace("\n", " ");
-                    let mut idx = vec![];
-                    let dl = desc.to_lowercase();
-                    for st in &opts.pkgs {
-                        let sl = st.to_lowercase();
-                        let mut y = dl.match_indices(&sl).collect::<Vec<_>>();
-                        idx.append(&mut y);
-                    }
-                    idx.sort();
-                    let mut idx2: Vec<(usize, &str)> = vec![];
-                    for i in idx {
-                        if {
-                            let mut b = true;
-                            idx2.retain(|j| {
-                                if j.0 == i.0 {
-                                    if i.1.len() > j.1.len() {
-                                        return false;
-                                    } else {
-                                        b = false;
-                                        return true;
-                                    }
-                                }
-                                return true;
-                            });
-                            b
-                        } {
-                            idx2.push(i);
-                        }
-                    }
-                    let mut offset = 0;
-                    for (i, st) in idx2 {
-                        let j = i + offset;
-                        let d = desc[j..j + st.len()].to_string();
-                        desc.replace_range(j..j + st.len(), &d.green().to_string());
-                        offset += 10;
+            let infos = match crate::npkgcmd::search::info(runner, &packages) {
+                Ok(x) => x,
+                Err(e) => {
+                    printerror(&format!("Could not read package information: {}", e));
+                    exit(1);
+                }
+            };
+            let missing = packages
+                .iter()
+                .zip(&infos)
+                .filter(|(_, x)| x.is_none())
+                .map(|(p, _)| p.to_string())
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                printerror(&format!("package not found: {}", missing.join(", ")));
+                exit(1);
+            }
+
+            // Find where the packages are installed
+            let mut installed = vec![];
+            let mut sources = vec![System];
+            if hm {
+                sources.push(Home);
+            }
+            sources.push(envmgr(&Target::default()));
+            for source in sources {
+                opts.pkgmgr = source;
+                let backend = crate::npkgcmd::operate::backend(&opts);
+                installed.append(&mut lstentries(runner, backend.as_ref()));
+            }
+
+            let mut entries = vec![];
+            for (i, info) in infos.into_iter().flatten().enumerate() {
+                let installed = installed
+                    .iter()
+                    .filter(|x| x.package == info.attr)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if json::enabled() {
+                    entries.push(json::InfoEntry { info, installed });
+                } else {
+                    if i > 0 {
+                        println!();
                     }
-                    println!("  {}", desc)
+                    ppinfo(&info, &installed);
                 }
-                None => {}
             }
-            println!();
+            if json::enabled() {
+                json::emit(&entries);
+            }
         }
-    } else if args.update {
-        if args.home {
-            if !hm {
-                printerror("home-manager is not installed");
+        Command::Cache => {
+            let status = crate::npkgcmd::search::cachestatus(runner);
+            if json::enabled() {
+                json::emit(&status);
+                exit(0);
+            }
+            ppcachestatus(&status);
+            for source in &status.sources {
+                println!();
+                ppcachestatus(source);
+            }
+        }
+        Command::Restore {
+            system,
+            home,
+            dryrun,
+            yes,
+            diffonly,
+            n,
+        } => {
+            if json::enabled() {
+                printerror("--json cannot be used with restore");
                 exit(1);
             }
-            opts.pkgmgr = Home;
-            println!(
-                "{} {}",
-                "Updating packages in".cyan(),
-                "home".green().bold()
-            );
-            crate::npkgcmd::operate::chnupdate(&opts);
-            pkupdate(&opts);
-        } else if args.system {
-            opts.pkgmgr = System;
-            println!(
-                "{} {}",
-                "Updating packages in".cyan(),
-                "system".green().bold()
-            );
-            crate::npkgcmd::operate::chnupdate(&opts);
-            pkupdate(&opts);
-        } else if args.env {
-            //Default env
-            opts.pkgmgr = Env;
-            println!(
-                "{} {}",
-                "Updating packages in".cyan(),
-                "nix environment".green().bold()
-            );
-            crate::npkgcmd::operate::chnupdate(&opts);
-            pkupdate(&opts);
-        } else {
-            crate::npkgcmd::operate::chnupdate(&opts);
-            opts.pkgmgr = System;
-            println!(
-                "{} {}",
-                "Updating packages in".cyan(),
-                "system".green().bold()
-            );
-            pkupdate(&opts);
-            if hm {
-                opts.pkgmgr = Home;
-                println!(
-                    "{} {}",
-                    "Updating packages in".cyan(),
-                    "home".green().bold()
-                );
-                pkupdate(&opts);
+            opts.dryrun = dryrun;
+            opts.yes = yes;
+            opts.diffonly = diffonly;
+            let target = Target {
+                system,
+                home,
+                ..Default::default()
+            };
+            opts.pkgmgr = location(&target, System, hm);
+            let backend = crate::npkgcmd::operate::backend(&opts);
+            match n {
+                Some(n) => pkrestore(runner, backend.as_ref(), &opts, n),
+                None => {
+                    ppbackups(backend.as_ref());
+                    // Without a target the backups of both configurations are listed
+                    if hm && !home && !system {
+                        opts.pkgmgr = Home;
+                        ppbackups(crate::npkgcmd::operate::backend(&opts).as_ref());
+                    }
+                }
             }
-            opts.pkgmgr = Env;
-            println!(
-                "{} {}",
-                "Updating packages in".cyan(),
-                "nix environment".green().bold()
-            );
-            pkupdate(&opts);
         }
-    } else {
-        printerror("no operation specified");
-        println!("Try 'npkg --help' for more information.");
-        exit(-1);
     }
 }
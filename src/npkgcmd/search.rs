@@ -1,244 +1,817 @@
-use crate::npkgcmd::PkgData;
-use bimap;
-use brotli;
-use curl::easy::Easy;
+use crate::npkgcmd::{
+    config::{checkconfig, readconfig, CacheBuilder},
+    PkgData,
+};
+use npkg::{
+    writeindex, CommandRunner, DownloadOptions, Index, IndexError, NixosVersion, PkgInfo, Query,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use owo_colors::*;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
 use std::{
+    cell::RefCell,
     collections::HashMap,
     env,
-    fs::{self, File},
-    io::{Read, Write},
+    fs,
+    io::Write,
     path::Path,
     process::Command,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-#[derive(Serialize, Deserialize, Debug)]
-struct PackageBase {
-    packages: HashMap<String, Package>,
+/// Where the package list of each NixOS release is published
+const PACKAGESURL: &str =
+    "https://releases.nixos.org/nixos/{release}/nixos-{version}/packages.json.br";
+
+/// The Nix User Repository, evaluated for the `nur` source
+const NURURL: &str = "https://github.com/nix-community/NUR/archive/master.tar.gz";
+
+/// A package found by [`search`]
+pub struct Found {
+    /// The package as found in the first source that has it
+    pub pkg: PkgData,
+    /// Name of every source that has the package and the version it offers, main source first
+    pub sources: Vec<(String, String)>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Package {
-    name: String,
-    pname: String,
-    version: String,
-    meta: Meta,
+/// Searches all sources for packages matching `query`, best match first
+///
+/// Only the main source has to be searchable, other sources that fail are skipped with a warning.
+pub fn search(runner: &dyn CommandRunner, query: &[String]) -> Result<Vec<Found>, String> {
+    let query = Query::parse(query).map_err(|e| e.to_string())?;
+    let (sources, builder) = cacheconfig();
+
+    let mut found: Vec<Found> = vec![];
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (i, source) in sources.iter().enumerate() {
+        let index = match sourceindex(runner, source, builder) {
+            Ok(x) => x,
+            Err(e) if i == 0 => return Err(e),
+            Err(e) => {
+                println!("{} could not search {}: {}", "warning:".yellow(), source.name, e);
+                continue;
+            }
+        };
+        for pkg in index.query(&query) {
+            let version = (source.name.to_string(), pkg.version.to_string());
+            match seen.get(&pkg.pname) {
+                Some(&x) => {
+                    found[x].pkg.score = found[x].pkg.score.max(pkg.score);
+                    found[x].sources.push(version);
+                }
+                None => {
+                    seen.insert(pkg.pname.to_string(), found.len());
+                    found.push(Found {
+                        pkg,
+                        sources: vec![version],
+                    });
+                }
+            }
+        }
+    }
+    // Stable, so packages of the main source stay first among equally good matches
+    found.sort_by_key(|x| std::cmp::Reverse(x.pkg.score));
+    Ok(found)
 }
-#[derive(Serialize, Deserialize, Debug)]
-struct Meta {
-    broken: Option<bool>,
-    description: Option<String>,
+
+/// Looks up all metadata of the packages with attributes `pkgs`, `None` for unknown packages
+pub fn info(runner: &dyn CommandRunner, pkgs: &[String]) -> Result<Vec<Option<PkgInfo>>, String> {
+    let index = openindex(runner)?;
+    Ok(pkgs
+        .iter()
+        .map(|x| index.byattr(x).map(|x| x.info()))
+        .collect())
 }
 
-pub fn search(query: &Vec<String>) -> Result<Vec<PkgData>, String> {
-    checkcache();
+/// Opens the search index of the main source as it is, without updating the cache
+pub fn cachedindex() -> Option<Index> {
+    Index::open(Path::new(&format!("{}/packages.idx", cachedir()))).ok()
+}
 
-    let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
-    let file = fs::read_to_string(format!("{}/packages.json", cachedir)).unwrap();
+/// Returns the packages in `pkgs` with a license that is not free
+///
+/// Only an existing search index is read, nothing is downloaded, so unknown packages are not reported.
+pub fn unfreepkgs(pkgs: &[String]) -> Vec<String> {
+    let index = match cachedindex() {
+        Some(x) => x,
+        None => return vec![],
+    };
+    pkgs.iter()
+        .filter(|x| index.byattr(x).map(|x| x.unfree).unwrap_or(false))
+        .cloned()
+        .collect()
+}
 
-    let data: PackageBase = serde_json::from_str(&file).expect("Failed to parse json");
-    let pkgs = data.packages.keys().filter(|x| {
-        let mut b = true;
-        let desc = &data.packages.get(&x.to_string()).unwrap().meta.description;
-        for q in query {
-            let desc = match desc {
-                Some(y) => y.to_lowercase().contains(q.to_lowercase().as_str()),
-                None => false,
-            };
-            b &= x.to_lowercase().contains(q.to_lowercase().as_str()) || desc;
+/// Returns the packages in `pkgs` that do not exist in `pkgset`, each with up to three similar
+/// attributes from the package cache
+///
+/// `pkgset` is the package set packages are installed from, see [`npkg::Backend::pkgset`], the
+/// main source if it is `None`. Packages given as `channel:attr` are looked up in that channel.
+///
+/// Packages of the main source are looked up in the existing search index first. Packages it does
+/// not have, because the cache is missing or out of date or the package comes from an overlay,
+/// are checked with `nix eval`. Nothing is downloaded, and packages that cannot be checked are
+/// assumed to exist.
+pub fn unknownpkgs(
+    runner: &dyn CommandRunner,
+    pkgset: Option<&str>,
+    pkgs: &[String],
+) -> Vec<(String, Vec<String>)> {
+    let index = cachedindex();
+    let (sources, _) = cacheconfig();
+    let main = sourcepkgs(&sources[0].key);
+    let mut unknown = vec![];
+    for pkg in pkgs {
+        let (set, attr) = match pkg.split_once(':') {
+            Some((channel, attr)) => (format!("(import <{}> {{}})", channel), attr),
+            None => (pkgset.unwrap_or(&main).to_string(), pkg.as_str()),
+        };
+        // The index only has the packages of the main source
+        if let Some(x) = &index {
+            if set == main && x.byattr(attr).is_some() {
+                continue;
+            }
+        }
+        match hasattr(runner, &set, attr) {
+            Ok(true) => {}
+            Ok(false) => {
+                let suggestions = match &index {
+                    Some(x) => x.suggest(attr, 3).iter().map(|x| x.to_string()).collect(),
+                    None => vec![],
+                };
+                unknown.push((pkg.to_string(), suggestions));
+            }
+            Err(e) => println!(
+                "{} could not check whether {} exists: {}",
+                "warning:".yellow(),
+                pkg,
+                e
+            ),
         }
-        b
-    });
+    }
+    unknown
+}
+
+/// Describes the packages [`unknownpkgs`] found, one line each with its suggestions
+pub fn unknownmsg(unknown: &[(String, Vec<String>)]) -> String {
+    unknown
+        .iter()
+        .map(|(pkg, suggestions)| match suggestions.len() {
+            0 => format!("unknown package '{}'", pkg),
+            _ => format!(
+                "unknown package '{}', did you mean {}?",
+                pkg,
+                suggestions
+                    .iter()
+                    .map(|x| format!("'{}'", x))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-    let mut out = vec![];
-    for i in pkgs {
-        let pkg = data.packages.get(i).unwrap();
-        out.push(PkgData {
-            pname: i.to_string(),
-            description: pkg.meta.description.clone(),
-            version: pkg.version.clone(),
-        });
+/// Searches for what was meant by `pkg`, a package [`unknownpkgs`] did not find
+///
+/// Returns `None` if nothing was found or there is no package cache yet, which searching would
+/// have to build first. Broken packages are left out.
+pub fn candidates(runner: &dyn CommandRunner, pkg: &str, limit: usize) -> Option<Vec<Found>> {
+    if !Path::is_file(Path::new(&format!("{}/packages.idx", cachedir()))) {
+        return None;
+    }
+    let mut found = search(runner, &[pkg.to_string()]).ok()?;
+    found.retain(|x| !x.pkg.broken);
+    found.truncate(limit);
+    if found.is_empty() {
+        None
+    } else {
+        Some(found)
     }
+}
 
-    out.sort_by_key(|x| x.pname.clone());
-    return Ok(out);
+/// Returns a Nix expression of the package set of the source `key`
+fn sourcepkgs(key: &str) -> String {
+    match flakeinput(key) {
+        Some((flake, input)) => format!(
+            "(builtins.getFlake {}).inputs.{}.legacyPackages.${{builtins.currentSystem}}",
+            nixstring(flake),
+            nixstring(input)
+        ),
+        None => format!("(import {} {{}})", key),
+    }
 }
 
-pub fn pname_to_name(query: &Vec<String>) -> Vec<String> {
-    checkcache();
+/// Evaluates whether the package set `pkgs`, a Nix expression, has the attribute `attr`, e.g.
+/// `python3Packages.requests`
+fn hasattr(runner: &dyn CommandRunner, pkgs: &str, attr: &str) -> Result<bool, String> {
+    // Every part is quoted, attributes like `2048-in-terminal` are not valid identifiers
+    let path = attr
+        .split('.')
+        .map(nixstring)
+        .collect::<Vec<_>>()
+        .join(".");
+    let out = runner
+        .output(Command::new("nix").args([
+            "eval",
+            "--impure",
+            "--json",
+            "--expr",
+            &format!("{} ? {}", pkgs, path),
+        ]))
+        .map_err(|e| e.to_string())?;
+    match serde_json::from_slice::<Value>(&out) {
+        Ok(Value::Bool(x)) => Ok(x),
+        _ => Err(format!(
+            "unexpected output '{}'",
+            String::from_utf8_lossy(&out).trim()
+        )),
+    }
+}
 
-    let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
-    let file = fs::read_to_string(format!("{}/pnameref.json", cachedir)).unwrap();
+/// Opens the search index of the main source, updating the cache first if needed
+pub fn openindex(runner: &dyn CommandRunner) -> Result<Index, String> {
+    let (sources, builder) = cacheconfig();
+    sourceindex(runner, &sources[0], builder)
+}
 
-    let data: bimap::BiHashMap<String, String> =
-        serde_json::from_str(&file).expect("Failed to parse json");
+/// Opens the search index of `source`, updating its cache first if needed
+fn sourceindex(
+    runner: &dyn CommandRunner,
+    source: &Source,
+    builder: CacheBuilder,
+) -> Result<Index, String> {
+    checkcache(runner, source, builder)?;
 
-    let mut pkgs = vec![];
-    for q in query {
-        pkgs.push(match data.get_by_left(q) {
-            Some(x) => x.to_string(),
-            None => q.to_string(),
-        });
+    let path = format!("{}/packages.idx", source.dir);
+    match Index::open(Path::new(&path)) {
+        // Index written by an older version of npkg
+        Err(IndexError::InvalidIndex) => {
+            updateindex(&source.dir)?;
+            Index::open(Path::new(&path)).map_err(|e| e.to_string())
+        }
+        x => x.map_err(|e| e.to_string()),
     }
-    return pkgs;
 }
 
-pub fn name_to_pname(query: &Vec<String>) -> Vec<String> {
-    checkcache();
+/// A package set npkg keeps a package cache of
+struct Source {
+    /// Name shown in search results, e.g. `nixos-unstable`
+    name: String,
+    /// A channel such as `<nixpkgs>`, an input of a flake such as `flake:/etc/nixos` for its
+    /// `nixpkgs` input or `flake:/etc/nixos#nixpkgs-unstable`, or `nur`
+    key: String,
+    /// Directory the cache is kept in
+    dir: String,
+}
 
-    let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
-    let file = fs::read_to_string(format!("{}/pnameref.json", cachedir)).unwrap();
-    let data: bimap::BiHashMap<String, String> =
-        serde_json::from_str(&file).expect("Failed to parse json");
-    let mut pkgs = vec![];
-    for q in query {
-        let n = match data.get_by_right(q) {
-            Some(x) => x.to_string(),
-            None => q.to_string(),
+impl Source {
+    /// Parses a source from the `sources` configuration option
+    ///
+    /// `spec` is a channel such as `nixos-unstable`, an input of the configured flake such as
+    /// `flake:nixpkgs-unstable`, an input of another flake such as `flake:/path#input`, or `nur`.
+    fn parse(spec: &str, flake: Option<&str>) -> Result<Source, String> {
+        let (name, key) = if spec == "nur" {
+            ("nur".to_string(), "nur".to_string())
+        } else if let Some(input) = spec.strip_prefix("flake:") {
+            let (path, input) = match (input.split_once('#'), flake) {
+                (Some((path, input)), _) => (path, input),
+                (None, Some(flake)) => (flake.split('#').next().unwrap_or_default(), input),
+                (None, None) => {
+                    return Err(format!(
+                        "source '{}' is a flake input, but no flake is configured",
+                        spec
+                    ))
+                }
+            };
+            (input.to_string(), flakekey(path, input))
+        } else {
+            let channel = spec.trim_start_matches('<').trim_end_matches('>');
+            (channel.to_string(), format!("<{}>", channel))
         };
-        pkgs.push(n);
-    }
-    return pkgs;
-}
-
-fn checkcache() {
-    let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
-
-    let vout = Command::new("nixos-version")
-        .arg("--json")
-        .output()
-        .unwrap();
-    let data: Value =
-        serde_json::from_str(&String::from_utf8_lossy(&vout.stdout)).expect("Failed to parse json");
-
-    let version = data.as_object().unwrap()["nixosVersion"].as_str().unwrap();
-
-    if !Path::is_dir(Path::new(&cachedir))
-        || !Path::is_file(Path::new(&format!("{}/version.json", &cachedir)))
-    {
-        println!("Updating cache");
-        setupcache();
-        let mut newver = fs::File::create(format!("{}/version.json", &cachedir)).unwrap();
-        newver.write_all(&vout.stdout).unwrap();
-    }
-
-    let file = fs::read_to_string(format!("{}/version.json", cachedir)).unwrap();
-    let olddata: Value = serde_json::from_str(&file).expect("Failed to parse json");
-
-    let oldversion = olddata.as_object().unwrap()["nixosVersion"]
-        .as_str()
-        .unwrap();
-
-    if version != oldversion {
-        println!("Out of date, updating cache");
-        setupcache();
-        let mut newver = fs::File::create(format!("{}/version.json", &cachedir)).unwrap();
-        newver.write_all(&vout.stdout).unwrap();
-    } else if !Path::is_file(Path::new(&format!("{}/packages.json", &cachedir))) {
-        println!("No packages.json, updating cache");
-        setupcache();
-        let mut newver = fs::File::create(format!("{}/version.json", &cachedir)).unwrap();
-        newver.write_all(&vout.stdout).unwrap();
-    } else if !Path::is_file(Path::new(&format!("{}/pnameref.json", &cachedir))) {
-        println!("Updating references");
-        updatepnameref();
-    }
-}
-
-fn setupcache() {
-    let vout = Command::new("nix-instantiate")
-        .arg("<nixpkgs/lib>")
-        .arg("-A")
-        .arg("version")
-        .arg("--eval")
-        .arg("--json")
-        .output()
-        .unwrap();
-    
-    let dlver = String::from_utf8_lossy(&vout.stdout)
-        .to_string()
-        .replace('"', "");
-
-    let mut relver = dlver.split('.').collect::<Vec<&str>>().join(".")[0..5].to_string();
-    
-    if dlver.len() >= 8 && &dlver[5..8] == "pre" {
-        relver = "unstable".to_string();
-    }
-
-    let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
-    fs::create_dir_all(&cachedir).expect("Failed to create cache directory");
-    let url = format!(
-        "https://releases.nixos.org/nixos/{}/nixos-{}/packages.json.br",
-        relver, dlver
-    );
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("invalid source '{}'", spec));
+        }
+        Ok(Source {
+            dir: format!("{}/sources/{}", cachedir(), name),
+            name,
+            key,
+        })
+    }
+}
 
-    if Path::is_file(Path::new(&format!("{}/packages.json", &cachedir))) {
-        fs::remove_file(Path::new(&format!("{}/packages.json", &cachedir)))
-            .expect("Failed to remove file");
+/// Source key of the input `input` of the flake at `path`
+fn flakekey(path: &str, input: &str) -> String {
+    if input == "nixpkgs" {
+        format!("flake:{}", path)
+    } else {
+        format!("flake:{}#{}", path, input)
     }
+}
+
+/// The nixpkgs the package cache was built from
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+struct CacheVersion {
+    /// The key of the source, see [`Source::key`]
+    source: String,
+    /// Version of the nixpkgs lib, which ends with the revision, e.g. `22.11.20221201.abcdef`
+    version: String,
+    #[serde(default)]
+    builder: CacheBuilder,
+    /// Unix time the cache was built
+    updated: u64,
+}
+
+/// State of the package cache, as shown by `--cache-status`
+#[derive(Serialize)]
+pub struct CacheStatus {
+    /// Name of the source shown in search results
+    pub name: String,
+    pub dir: String,
+    pub source: String,
+    pub builder: CacheBuilder,
+    /// nixpkgs version the cache was built from
+    pub cached: Option<String>,
+    /// nixpkgs version currently in use, `None` if it could not be evaluated
+    pub current: Option<String>,
+    pub uptodate: bool,
+    /// Unix time the cache was built
+    pub updated: Option<u64>,
+    /// Number of packages in the search index
+    pub packages: Option<usize>,
+    /// State of the caches of the other configured sources
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<CacheStatus>,
+}
 
-    let mut dst = Vec::new();
-    let mut easy = Easy::new();
-    easy.url(&url).unwrap();
+fn cachedir() -> String {
+    format!("{}/.cache/npkg", env::var("HOME").unwrap())
+}
+
+/// Returns the sources packages are searched in and how their caches are built, following the
+/// npkg configuration
+///
+/// The main source comes first. It is the `nixpkgs` input when a flake is configured, and the
+/// configured channel or `<nixpkgs>` otherwise. It is followed by the configured `sources`.
+fn cacheconfig() -> (Vec<Source>, CacheBuilder) {
+    let config = readconfig(checkconfig());
+    let flake = config.flake.as_deref();
+    let main = match (flake, &config.channel) {
+        (Some(flake), _) => Source {
+            name: "nixpkgs".to_string(),
+            key: flakekey(flake.split('#').next().unwrap_or_default(), "nixpkgs"),
+            dir: cachedir(),
+        },
+        (None, Some(channel)) => Source {
+            name: channel.to_string(),
+            key: format!("<{}>", channel),
+            dir: cachedir(),
+        },
+        (None, None) => Source {
+            name: "nixpkgs".to_string(),
+            key: "<nixpkgs>".to_string(),
+            dir: cachedir(),
+        },
+    };
 
-    {
-        let mut transfer = easy.transfer();
-        transfer
-            .write_function(|data| {
-                dst.extend_from_slice(data);
-                Ok(data.len())
-            })
-            .unwrap();
-        transfer.perform().unwrap();
+    let mut sources = vec![main];
+    for spec in &config.sources {
+        match Source::parse(spec, flake) {
+            Ok(x) if sources.iter().any(|s| s.key == x.key || s.name == x.name) => {}
+            Ok(x) => sources.push(x),
+            Err(e) => println!("{} {}", "warning:".yellow(), e),
+        }
     }
+    (sources, config.cache)
+}
 
-    {
-        let mut file = File::create(format!("{}/packages.json", &cachedir).as_str())
-            .expect("Failed to create file");
-        let mut reader = brotli::Decompressor::new(
-            dst.as_slice(),
-            4096, // buffer size
-        );
-        let mut buf = [0u8; 4096];
-        loop {
-            match reader.read(&mut buf[..]) {
-                Err(e) => {
-                    if let std::io::ErrorKind::Interrupted = e.kind() {
-                        continue;
-                    }
-                    panic!("{}", e);
-                }
-                Ok(size) => {
-                    if size == 0 {
-                        break;
-                    }
-                    match file.write_all(&buf[..size]) {
-                        Err(e) => panic!("{}", e),
-                        Ok(_) => {}
-                    }
-                }
+/// How the cache of `source` is built, `builder` unless the source cannot be built with it
+fn sourcebuilder(source: &Source, builder: CacheBuilder) -> CacheBuilder {
+    match source.key.as_str() {
+        // NUR has no release package lists and is not a flake nix search can use
+        "nur" => CacheBuilder::NixEnv,
+        _ => builder,
+    }
+}
+
+/// Splits the key of a flake input source into the path of the flake and the input
+fn flakeinput(key: &str) -> Option<(&str, &str)> {
+    let flake = key.strip_prefix("flake:")?;
+    Some(flake.split_once('#').unwrap_or((flake, "nixpkgs")))
+}
+
+/// Quotes `s` as a Nix string, so paths and attributes cannot end it or interpolate
+fn nixstring(s: &str) -> String {
+    let s = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\$");
+    format!("\"{}\"", s)
+}
+
+/// Evaluates the version of the nixpkgs lib of `source`, which changes with every revision
+///
+/// NUR has no version, the store path of its latest revision is used instead.
+fn nixpkgsversion(runner: &dyn CommandRunner, source: &str) -> Result<String, String> {
+    let out = match flakeinput(source) {
+        Some((flake, input)) => runner.output(Command::new("nix").args([
+            "eval",
+            "--impure",
+            "--json",
+            "--expr",
+            &format!(
+                "(builtins.getFlake {}).inputs.{}.lib.version",
+                nixstring(flake),
+                nixstring(input)
+            ),
+        ])),
+        None if source == "nur" => runner.output(Command::new("nix-instantiate").args([
+            "--eval",
+            "--json",
+            "--expr",
+            &format!("builtins.fetchTarball \"{}\"", NURURL),
+        ])),
+        None => runner.output(
+            Command::new("nix-instantiate")
+                .arg(format!("{}/lib>", source.trim_end_matches('>')))
+                .arg("-A")
+                .arg("version")
+                .arg("--eval")
+                .arg("--json"),
+        ),
+    }
+    .map_err(|e| e.to_string())?;
+    match serde_json::from_slice::<Value>(&out) {
+        Ok(Value::String(x)) if !x.is_empty() => Ok(x),
+        _ => Err(format!(
+            "unexpected nixpkgs version '{}'",
+            String::from_utf8_lossy(&out).trim()
+        )),
+    }
+}
+
+fn readversion(dir: &str) -> Option<CacheVersion> {
+    let file = fs::read_to_string(format!("{}/version.json", dir)).ok()?;
+    // Caches written by older versions of npkg only store the NixOS version
+    serde_json::from_str(&file).ok()
+}
+
+fn writeversion(
+    dir: &str,
+    source: &str,
+    builder: CacheBuilder,
+    version: &str,
+) -> Result<(), String> {
+    let updated = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    let data = CacheVersion {
+        source: source.to_string(),
+        version: version.to_string(),
+        builder,
+        updated,
+    };
+    fs::write(
+        format!("{}/version.json", dir),
+        serde_json::to_string_pretty(&data).unwrap(),
+    )
+    .map_err(|e| format!("Could not write cache version: {}", e))
+}
+
+/// Builds the cache of `source` again if its nixpkgs revision changed since it was cached
+///
+/// If the revision cannot be evaluated, an existing cache is used as is.
+fn checkcache(
+    runner: &dyn CommandRunner,
+    source: &Source,
+    builder: CacheBuilder,
+) -> Result<(), String> {
+    let cachedir = &source.dir;
+    let haspackages = Path::is_file(Path::new(&format!("{}/packages.json", cachedir)));
+    let builder = sourcebuilder(source, builder);
+
+    let version = match nixpkgsversion(runner, &source.key) {
+        Ok(x) => x,
+        Err(e) if haspackages => {
+            println!(
+                "{} could not evaluate the nixpkgs version of {} ({}), using cached packages",
+                "warning:".yellow(),
+                source.key,
+                e
+            );
+            if !Path::is_file(Path::new(&format!("{}/packages.idx", cachedir))) {
+                println!("Updating search index");
+                updateindex(cachedir)?;
+            }
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(format!(
+                "could not evaluate the nixpkgs version of {}: {}",
+                source.key, e
+            ))
+        }
+    };
+
+    match readversion(cachedir) {
+        None => println!("Updating {} cache", source.name),
+        Some(x) if x.source != source.key || x.version != version || x.builder != builder => {
+            println!("Out of date, updating {} cache", source.name)
+        }
+        Some(_) if !haspackages => println!("No packages.json, updating {} cache", source.name),
+        Some(_) => {
+            if !Path::is_file(Path::new(&format!("{}/packages.idx", cachedir))) {
+                println!("Updating search index");
+                updateindex(cachedir)?;
             }
+            return Ok(());
         }
     }
-    updatepnameref();
+    buildcache(runner, source, builder, &version)
 }
 
-fn updatepnameref() {
-    let cachedir = format!("{}/.cache/npkg", env::var("HOME").unwrap());
-    let file = fs::read_to_string(format!("{}/packages.json", cachedir)).unwrap();
-    let data: PackageBase = serde_json::from_str(&file).expect("Failed to parse json");
-    let mut hmap = bimap::BiHashMap::new();
-    for (s, pkg) in data.packages {
-        hmap.insert(s, pkg.name.to_string());
+/// Builds the caches of all sources for their current nixpkgs revision, even if they are up to date
+pub fn refreshcache(runner: &dyn CommandRunner) -> Result<(), String> {
+    let (sources, builder) = cacheconfig();
+    for source in &sources {
+        let builder = sourcebuilder(source, builder);
+        let version = nixpkgsversion(runner, &source.key).map_err(|e| {
+            format!(
+                "could not evaluate the nixpkgs version of {}: {}",
+                source.key, e
+            )
+        })?;
+        println!("Updating {} cache", source.name);
+        buildcache(runner, source, builder, &version)?;
     }
-    let out = match serde_json::to_string(&hmap) {
-        Ok(x) => x,
-        Err(e) => panic!("{}", e),
+    Ok(())
+}
+
+/// Reports which nixpkgs the caches were built from and whether they are up to date
+pub fn cachestatus(runner: &dyn CommandRunner) -> CacheStatus {
+    let (sources, builder) = cacheconfig();
+    let mut statuses = sources
+        .iter()
+        .map(|x| sourcestatus(runner, x, sourcebuilder(x, builder)))
+        .collect::<Vec<_>>();
+    let mut status = statuses.remove(0);
+    status.sources = statuses;
+    status
+}
+
+fn sourcestatus(runner: &dyn CommandRunner, source: &Source, builder: CacheBuilder) -> CacheStatus {
+    let dir = &source.dir;
+    let cached = readversion(dir);
+    let current = nixpkgsversion(runner, &source.key).ok();
+    let packages = Index::open(Path::new(&format!("{}/packages.idx", dir)))
+        .ok()
+        .map(|x| x.len());
+    let uptodate = match (&cached, &current) {
+        (Some(x), Some(current)) => {
+            x.source == source.key
+                && &x.version == current
+                && x.builder == builder
+                && Path::is_file(Path::new(&format!("{}/packages.json", dir)))
+        }
+        _ => false,
+    };
+    CacheStatus {
+        name: source.name.to_string(),
+        dir: dir.to_string(),
+        source: source.key.to_string(),
+        builder,
+        cached: cached.as_ref().map(|x| x.version.to_string()),
+        current,
+        uptodate,
+        updated: cached.map(|x| x.updated),
+        packages,
+        sources: vec![],
+    }
+}
+
+/// Fills the package cache from `source` with `builder`, then builds the search index and records
+/// the nixpkgs `version` it was built from
+fn buildcache(
+    runner: &dyn CommandRunner,
+    source: &Source,
+    builder: CacheBuilder,
+    version: &str,
+) -> Result<(), String> {
+    fs::create_dir_all(&source.dir).map_err(|e| e.to_string())?;
+    match builder {
+        CacheBuilder::Download => setupcache(&source.dir, version)?,
+        CacheBuilder::NixEnv => nixenvcache(runner, source)?,
+        CacheBuilder::NixSearch => nixsearchcache(runner, source)?,
+    }
+    updateindex(&source.dir)?;
+    writeversion(&source.dir, &source.key, builder, version)
+}
+
+/// Returns an expression `nix-env -f` can evaluate for `source`
+///
+/// Flake inputs are looked up in the store, NUR is fetched and channels are passed as is.
+fn nixpkgspath(runner: &dyn CommandRunner, source: &str) -> Result<String, String> {
+    if source == "nur" {
+        // Evaluating the version fetched it already
+        let version = nixpkgsversion(runner, source)?;
+        return Ok(version);
+    }
+    let (flake, input) = match flakeinput(source) {
+        Some(x) => x,
+        None => return Ok(source.to_string()),
+    };
+    let out = runner
+        .output(Command::new("nix").args([
+            "eval",
+            "--impure",
+            "--raw",
+            "--expr",
+            &format!(
+                "(builtins.getFlake {}).inputs.{}.outPath",
+                nixstring(flake),
+                nixstring(input)
+            ),
+        ]))
+        .map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&out).trim().to_string())
+}
+
+/// Builds `packages.json` by evaluating every package with `nix-env`
+///
+/// The output of nix-env is written straight to the file, so the whole package set never has
+/// to be held in memory.
+fn nixenvcache(runner: &dyn CommandRunner, source: &Source) -> Result<(), String> {
+    let file = nixpkgspath(runner, &source.key)?;
+    println!("Evaluating packages in {} with nix-env, this can take a while", file);
+    let path = format!("{}/packages.json", source.dir);
+    let tmp = format!("{}.tmp", path);
+    let mut out = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp)
+        .map_err(|e| e.to_string())?;
+    // nix-env prints the packages without the object around them in the release package list.
+    // It writes through a duplicate of the file handle, so its output ends up after the prefix.
+    out.write_all(b"{\"packages\": ").map_err(|e| e.to_string())?;
+    let stdout = out.try_clone().map_err(|e| e.to_string())?;
+    let mut cmd = Command::new("nix-env");
+    cmd.args(["-f", &file, "-qaP", "--json", "--meta"]);
+    if source.key == "nur" {
+        // NUR packages are built with the local nixpkgs and kept under `repos.<user>`
+        cmd.args(["--arg", "pkgs", "import <nixpkgs> {}", "-A", "repos"]);
+    }
+    let res = runner.run(cmd.stdout(stdout));
+    if let Err(e) = res {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.to_string());
+    }
+    out.write_all(b"}\n").map_err(|e| e.to_string())?;
+    fs::rename(&tmp, &path).map_err(|e| e.to_string())
+}
+
+/// Builds `packages.json` from the output of `nix search`, which only includes descriptions
+fn nixsearchcache(runner: &dyn CommandRunner, source: &Source) -> Result<(), String> {
+    let mut cmd = Command::new("nix");
+    cmd.args(["search", "--json"]);
+    match flakeinput(&source.key) {
+        // Resolves the input to the revision locked by the flake
+        Some((flake, input)) => cmd.args(["--inputs-from", flake, input, "^"]),
+        None => cmd.args(["--file", &source.key, "", "^"]),
+    };
+    println!(
+        "Evaluating packages in {} with nix search, this can take a while",
+        source.key
+    );
+    let out = runner.output(&mut cmd).map_err(|e| e.to_string())?;
+    let found: serde_json::Map<String, Value> =
+        serde_json::from_slice(&out).map_err(|e| e.to_string())?;
+
+    let mut packages = serde_json::Map::new();
+    for (attr, pkg) in found {
+        // Flake outputs are prefixed with `legacyPackages.<system>.`
+        let attr = match attr.strip_prefix("legacyPackages.") {
+            Some(x) => x.split_once('.').map(|x| x.1).unwrap_or(x).to_string(),
+            None => attr,
+        };
+        let pname = pkg["pname"].as_str().unwrap_or(&attr).to_string();
+        let version = pkg["version"].as_str().unwrap_or_default().to_string();
+        let name = if version.is_empty() {
+            pname
+        } else {
+            format!("{}-{}", pname, version)
+        };
+        packages.insert(
+            attr,
+            serde_json::json!({
+                "name": name,
+                "version": version,
+                "meta": { "description": pkg["description"] },
+            }),
+        );
+    }
+    let data = serde_json::json!({ "packages": packages });
+    let path = format!("{}/packages.json", source.dir);
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, serde_json::to_vec(&data).unwrap()).map_err(|e| e.to_string())?;
+    fs::rename(&tmp, &path).map_err(|e| e.to_string())
+}
+
+/// Downloads the package list of nixpkgs version `dlver` from releases.nixos.org to `dir`
+fn setupcache(dir: &str, dlver: &str) -> Result<(), String> {
+    let template = readconfig(checkconfig())
+        .packagesurl
+        .unwrap_or_else(|| PACKAGESURL.to_string());
+    let url = packagesurl(&template, dlver)?;
+    let dest = format!("{}/packages.json", dir);
+
+    if let Some(path) = localpath(&url) {
+        return npkg::extract(Path::new(&path), Path::new(&dest))
+            .map_err(|e| format!("Could not read package list {}: {}", path, e));
+    }
+
+    // Not every release publishes a checksum, the download is still used without one
+    let sha256 = npkg::checksum(&url).ok().flatten();
+    let bar: RefCell<Option<ProgressBar>> = RefCell::new(None);
+    let progress = |done: u64, total: Option<u64>| {
+        let mut bar = bar.borrow_mut();
+        let bar = bar.get_or_insert_with(|| progressbar(total));
+        bar.set_position(done);
     };
-    let mut outfile = File::create(format!("{}/pnameref.json", &cachedir).as_str())
-        .expect("Failed to create file");
-    outfile
-        .write_all(out.as_bytes())
-        .expect("Failed to write file");
+    let res = npkg::download(
+        &url,
+        Path::new(&dest),
+        &DownloadOptions {
+            sha256,
+            progress: Some(&progress),
+            ..Default::default()
+        },
+    );
+    if let Some(bar) = bar.borrow_mut().take() {
+        bar.finish_and_clear();
+    }
+    res.map_err(|e| format!("Could not download package list: {}", e))
+}
+
+/// Fills in the `{release}` and `{version}` of the nixpkgs version `dlver` in `template`
+fn packagesurl(template: &str, dlver: &str) -> Result<String, String> {
+    if !template.contains("{release}") && !template.contains("{version}") {
+        return Ok(template.to_string());
+    }
+    let version = NixosVersion::parse(dlver)
+        .ok_or_else(|| format!("Could not parse nixpkgs version '{}'", dlver))?;
+    Ok(template
+        .replace("{release}", &version.release)
+        .replace("{version}", &version.version))
+}
+
+/// The path `url` points to if it is a local file
+fn localpath(url: &str) -> Option<String> {
+    if let Some(path) = url.strip_prefix("file://") {
+        Some(path.to_string())
+    } else if let Some(path) = url.strip_prefix("~/") {
+        Some(format!("{}/{}", env::var("HOME").unwrap(), path))
+    } else if url.starts_with('/') {
+        Some(url.to_string())
+    } else {
+        None
+    }
+}
+
+/// Progress bar for a download of `total` bytes, a spinner if the size is unknown
+fn progressbar(total: Option<u64>) -> ProgressBar {
+    match total {
+        Some(total) => {
+            let bar = ProgressBar::new(total);
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "Downloading package list [{bar:30}] {bytes}/{total_bytes} ({eta})",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            );
+            bar
+        }
+        None => {
+            let bar = ProgressBar::new_spinner();
+            bar.set_style(
+                ProgressStyle::with_template("{spinner} Downloading package list {bytes}").unwrap(),
+            );
+            bar
+        }
+    }
+}
+
+/// Builds the search index from `packages.json` in `cachedir`
+fn updateindex(cachedir: &str) -> Result<(), String> {
+    writeindex(
+        Path::new(&format!("{}/packages.json", cachedir)),
+        Path::new(&format!("{}/packages.idx", cachedir)),
+    )
+    .map_err(|e| format!("Could not write search index: {}", e))?;
+    // Name lookups used to be stored separately, they are part of the index now
+    let _ = fs::remove_file(format!("{}/pnameref.json", cachedir));
+    Ok(())
 }
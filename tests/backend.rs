@@ -0,0 +1,214 @@
+use npkg::*;
+use std::fs;
+
+const PACKAGES: &[u8] = include_bytes!("fixtures/packages.json");
+
+fn index() -> Option<Index> {
+    Some(Index::from_bytes(buildindex(PACKAGES).unwrap()).unwrap())
+}
+
+fn pkgs(x: &[&str]) -> Vec<String> {
+    x.iter().map(|x| x.to_string()).collect()
+}
+
+#[test]
+fn system_lists_and_edits_configuration() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("configuration.nix");
+    fs::copy("tests/fixtures/configuration.nix", &config).unwrap();
+    fs::copy(
+        "tests/fixtures/packages.nix",
+        dir.path().join("packages.nix"),
+    )
+    .unwrap();
+    let config = config.to_string_lossy().to_string();
+    let runner = FakeRunner::new();
+    let backend = System {
+        config: config.to_string(),
+        target: config.to_string(),
+        attr: None,
+        flake: None,
+    };
+    assert_eq!(
+        backend.list(&runner).unwrap(),
+        pkgs(&["git", "htop", "vim"])
+    );
+
+    let edits = backend.install(&runner, &pkgs(&["hello"])).unwrap();
+    assert_eq!(edits.len(), 1);
+    assert_eq!(edits[0].file, config);
+    assert!(edits[0].new.contains("hello"));
+    // Edits are only returned, not written
+    assert!(!fs::read_to_string(&config).unwrap().contains("hello"));
+
+    // git is declared in both files
+    let edits = backend.remove(&runner, &pkgs(&["git"])).unwrap();
+    assert_eq!(edits.len(), 2);
+    assert!(edits.iter().all(|e| !e.new.contains("git")));
+    assert!(runner.calls().is_empty());
+
+    backend.apply(&runner).unwrap();
+    let flake = System {
+        flake: Some("/etc/nixos#host".to_string()),
+        ..backend
+    };
+    flake.update(&runner).unwrap();
+    assert_eq!(
+        runner.calls(),
+        vec![
+            "nixos-rebuild switch --use-remote-sudo",
+            "nixos-rebuild switch --flake /etc/nixos#host --use-remote-sudo"
+        ]
+    );
+}
+
+#[test]
+fn home_manages_other_lists() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("home.nix");
+    fs::copy("tests/fixtures/home.nix", &config).unwrap();
+    let config = config.to_string_lossy().to_string();
+    let runner = FakeRunner::new();
+    let backend = Home {
+        config: config.to_string(),
+        target: config.to_string(),
+        attr: Some("fonts.packages".to_string()),
+        flake: None,
+    };
+    assert!(backend.list(&runner).unwrap().is_empty());
+    let edits = backend.install(&runner, &pkgs(&["dejavu_fonts"])).unwrap();
+    assert!(edits[0].new.contains("fonts.packages"));
+    backend.apply(&runner).unwrap();
+    assert_eq!(runner.calls(), vec!["home-manager switch"]);
+}
+
+#[test]
+fn env_translates_names_with_index() {
+    let runner = FakeRunner::new();
+    runner.respond(
+        "nix-env -q --json",
+        0,
+        "{\"hello-2.12.1\": {\"name\": \"hello-2.12.1\"}, \"foo-1.0\": {\"name\": \"foo-1.0\"}}",
+        "",
+    );
+    let backend = Env {
+        channel: Some("nixos".to_string()),
+        index: index(),
+    };
+    assert_eq!(backend.list(&runner).unwrap(), pkgs(&["foo-1.0", "hello"]));
+    assert!(backend
+        .remove(&runner, &pkgs(&["hello"]))
+        .unwrap()
+        .is_empty());
+    assert!(backend
+        .install(&runner, &pkgs(&["git"]))
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        runner.calls()[1..],
+        ["nix-env -e hello-2.12.1", "nix-env -iA nixos.git"]
+    );
+
+    // Without an index names are used as is
+    let backend = Env {
+        channel: None,
+        index: None,
+    };
+    assert_eq!(
+        backend.list(&runner).unwrap(),
+        pkgs(&["foo-1.0", "hello-2.12.1"])
+    );
+}
+
+#[test]
+fn env_installs_from_default_channel() {
+    let runner = FakeRunner::new();
+    runner.respond(
+        "nix-channel --list",
+        0,
+        "nixpkgs https://nixos.org/channels/nixpkgs-unstable\n",
+        "",
+    );
+    let backend = Env {
+        channel: None,
+        index: None,
+    };
+    backend.install(&runner, &pkgs(&["hello"])).unwrap();
+    assert_eq!(runner.calls()[1], "nix-env -iA nixpkgs.hello");
+}
+
+#[test]
+fn env_list_fails_on_invalid_output() {
+    let runner = FakeRunner::new();
+    runner.respond("nix-env -q --json", 0, "not json", "");
+    let backend = Env {
+        channel: None,
+        index: index(),
+    };
+    assert!(matches!(
+        backend.list(&runner),
+        Err(ExecuteError::ListError(_))
+    ));
+}
+
+#[test]
+fn profile_removes_elements() {
+    let runner = FakeRunner::new();
+    runner.respond(
+        "nix profile list --json",
+        0,
+        "{\"elements\": {\"hello\": {\"attrPath\": \"legacyPackages.x86_64-linux.hello\"}, \"git\": {\"attrPath\": \"legacyPackages.x86_64-linux.git\"}}}",
+        "",
+    );
+    assert_eq!(Profile.list(&runner).unwrap(), pkgs(&["git", "hello"]));
+    Profile.remove(&runner, &pkgs(&["hello", "vim"])).unwrap();
+    Profile.update(&runner).unwrap();
+    assert_eq!(
+        runner.calls()[2..],
+        ["nix profile remove hello", "nix profile upgrade --all"]
+    );
+}
+
+#[test]
+fn backends_are_described() {
+    let backends: Vec<Box<dyn Backend>> = vec![
+        Box::new(Profile),
+        Box::new(Env {
+            channel: None,
+            index: None,
+        }),
+    ];
+    assert_eq!(
+        backends.iter().map(|x| x.describe()).collect::<Vec<_>>(),
+        ["nix profile", "nix environment"]
+    );
+    assert!(backends
+        .iter()
+        .all(|x| x.config().is_none() && x.target().is_none() && !x.root()));
+
+    // Packages are checked in the package set they are installed from
+    let runner = FakeRunner::new();
+    assert_eq!(
+        backends
+            .iter()
+            .map(|x| x.pkgset(&runner).unwrap())
+            .collect::<Vec<_>>(),
+        [
+            "(builtins.getFlake \"nixpkgs\").legacyPackages.${builtins.currentSystem}",
+            "(import <nixos> {})"
+        ]
+    );
+
+    let home = Home {
+        config: "home.nix".to_string(),
+        target: "packages.nix".to_string(),
+        attr: None,
+        flake: Some("/home/me/nix#me".to_string()),
+    };
+    assert_eq!(home.source(), "home");
+    assert_eq!(home.config(), Some("home.nix"));
+    assert_eq!(home.target(), Some("packages.nix"));
+    assert_eq!(home.flake(), Some("/home/me/nix#me"));
+    assert!(!home.root());
+    assert_eq!(home.pkgset(&runner), None);
+}
@@ -0,0 +1,1347 @@
+use std::{
+    env, fs,
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+use tempfile::TempDir;
+
+/// A fake home directory with scripted `nixos-rebuild`, `home-manager`, `nix-env` and friends on `PATH`
+///
+/// Every fake command appends its command line to `log` and exits with the code in
+/// `FAKE_EXIT_<NAME>` (defaulting to 0), writing `FAKE_STDOUT_<NAME>` to stdout and
+/// `FAKE_STDERR_<NAME>` to stderr.
+struct Sandbox {
+    dir: TempDir,
+}
+
+const NIXPKGS_VERSION: &str = "22.11.20221201.abcdef";
+
+const FAKES: &[(&str, &str)] = &[
+    ("nixos-rebuild", "NIXOS_REBUILD"),
+    ("home-manager", "HOME_MANAGER"),
+    ("nix-env", "NIX_ENV"),
+    ("nix-channel", "NIX_CHANNEL"),
+    ("nix", "NIX"),
+];
+
+impl Sandbox {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        for (name, var) in FAKES {
+            script(
+                &bin.join(name),
+                &format!(
+                    "echo \"{} $*\" >> \"$HOME/log\"\nprintf \"%s\" \"$FAKE_STDOUT_{}\"\nprintf \"%b\" \"$FAKE_STDERR_{}\" >&2\nexit ${{FAKE_EXIT_{}:-0}}\n",
+                    name, var, var, var
+                ),
+            );
+        }
+        script(&bin.join("sudo"), "exec \"$@\"\n");
+        // Evaluates the nixpkgs version, which the package cache is keyed on
+        script(
+            &bin.join("nix-instantiate"),
+            &format!(
+                "echo \"nix-instantiate $*\" >> \"$HOME/log\"\nprintf '\"%s\"' \"${{FAKE_NIXPKGS_VERSION:-{}}}\"\nexit ${{FAKE_EXIT_NIX_INSTANTIATE:-0}}\n",
+                NIXPKGS_VERSION
+            ),
+        );
+
+        let cfgdir = dir.path().join(".config/npkg");
+        fs::create_dir_all(&cfgdir).unwrap();
+        fs::copy(
+            "tests/fixtures/configuration.nix",
+            dir.path().join("configuration.nix"),
+        )
+        .unwrap();
+        fs::copy(
+            "tests/fixtures/packages.nix",
+            dir.path().join("packages.nix"),
+        )
+        .unwrap();
+        fs::copy("tests/fixtures/home.nix", dir.path().join("home.nix")).unwrap();
+        fs::write(
+            cfgdir.join("config.json"),
+            format!(
+                "{{\"systemconfig\": \"{0}/configuration.nix\", \"homeconfig\": \"{0}/home.nix\", \"flake\": null}}",
+                dir.path().display()
+            ),
+        )
+        .unwrap();
+        Sandbox { dir }
+    }
+
+    /// Fills the search cache from the fixture `packages.json`
+    fn cache(&self) {
+        let cachedir = self.path(".cache/npkg");
+        fs::create_dir_all(&cachedir).unwrap();
+        fs::copy(
+            "tests/fixtures/packages.json",
+            cachedir.join("packages.json"),
+        )
+        .unwrap();
+        fs::write(
+            cachedir.join("version.json"),
+            format!(
+                "{{\"source\": \"<nixpkgs>\", \"version\": \"{}\", \"updated\": 1669852800}}\n",
+                NIXPKGS_VERSION
+            ),
+        )
+        .unwrap();
+    }
+
+    /// Adds `extra` JSON fields to the npkg configuration
+    fn config(&self, extra: &str) {
+        let file = self.path(".config/npkg/config.json");
+        let cfg = fs::read_to_string(&file).unwrap();
+        fs::write(&file, format!("{}, {}}}", cfg.trim_end_matches('}'), extra)).unwrap();
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.path().join(file)
+    }
+
+    fn read(&self, file: &str) -> String {
+        fs::read_to_string(self.path(file)).unwrap_or_default()
+    }
+
+    fn npkg(&self, args: &[&str], env: &[(&str, &str)]) -> Output {
+        self.command(args, env).output().unwrap()
+    }
+
+    /// Runs npkg with `input` written to its stdin, which is not a terminal
+    fn npkginput(&self, args: &[&str], env: &[(&str, &str)], input: &str) -> Output {
+        let mut child = self
+            .command(args, env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // npkg may exit without reading its input
+        let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+        child.wait_with_output().unwrap()
+    }
+
+    fn command(&self, args: &[&str], env: &[(&str, &str)]) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_npkg"));
+        cmd.args(args)
+            .env("HOME", self.dir.path())
+            .env_remove("XDG_STATE_HOME")
+            .env(
+                "PATH",
+                format!(
+                    "{}:{}",
+                    self.path("bin").display(),
+                    env::var("PATH").unwrap_or_default()
+                ),
+            )
+            .envs(env.iter().cloned());
+        cmd
+    }
+}
+
+fn script(path: &Path, body: &str) {
+    fs::write(path, format!("#!/bin/sh\n{}", body)).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn system_install_rebuilds() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-iS", "-y", "hello"], &[]);
+    assert!(out.status.success());
+    assert!(sb.read("configuration.nix").contains("hello"));
+    assert!(sb
+        .read("log")
+        .contains("nixos-rebuild switch --use-remote-sudo"));
+}
+
+#[test]
+fn system_install_rolls_back_failed_rebuild() {
+    let sb = Sandbox::new();
+    let original = sb.read("configuration.nix");
+    let out = sb.npkg(
+        &["-iS", "-y", "helo"],
+        &[
+            ("FAKE_EXIT_NIXOS_REBUILD", "1"),
+            (
+                "FAKE_STDERR_NIXOS_REBUILD",
+                "error: undefined variable 'helo'\\n",
+            ),
+        ],
+    );
+    assert!(!out.status.success());
+    assert_eq!(sb.read("configuration.nix"), original);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("undefined variable 'helo'"));
+}
+
+#[test]
+fn system_dry_run_does_not_rebuild() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-iS", "-y", "-d", "hello"], &[]);
+    assert!(out.status.success());
+    assert!(sb.read("configuration.nix").contains("hello"));
+    assert!(!sb.read("log").contains("nixos-rebuild"));
+}
+
+#[test]
+fn home_remove_switches() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-rH", "-y", "firefox"], &[]);
+    assert!(out.status.success());
+    assert!(!sb.read("home.nix").contains("firefox"));
+    assert!(sb.read("log").contains("home-manager switch"));
+}
+
+#[test]
+fn home_remove_rolls_back_failed_switch() {
+    let sb = Sandbox::new();
+    let original = sb.read("home.nix");
+    let out = sb.npkg(
+        &["-rH", "-y", "firefox"],
+        &[("FAKE_EXIT_HOME_MANAGER", "1")],
+    );
+    assert!(!out.status.success());
+    assert_eq!(sb.read("home.nix"), original);
+}
+
+#[test]
+fn system_update_stops_on_channel_failure() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-uS"], &[("FAKE_EXIT_NIX_CHANNEL", "1")]);
+    assert!(!out.status.success());
+    assert!(!sb.read("log").contains("nixos-rebuild"));
+}
+
+#[test]
+fn system_update_rebuilds() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-uS"], &[]);
+    assert!(out.status.success());
+    let log = sb.read("log");
+    assert!(log.contains("nix-channel --update"));
+    assert!(log.contains("nixos-rebuild switch --use-remote-sudo"));
+}
+
+#[test]
+fn system_install_keeps_backup() {
+    let sb = Sandbox::new();
+    let original = sb.read("configuration.nix");
+    assert!(sb.npkg(&["-iS", "-y", "hello"], &[]).status.success());
+    let backups = fs::read_dir(sb.path(".local/state/npkg/backups/system"))
+        .unwrap()
+        .collect::<Vec<_>>();
+    assert_eq!(backups.len(), 1);
+    let backup = backups[0].as_ref().unwrap().path();
+    assert_eq!(fs::read_to_string(backup).unwrap(), original);
+    assert!(!sb.path(".configuration.nix.npkg-tmp").exists());
+}
+
+#[test]
+fn install_writes_through_symlink() {
+    let sb = Sandbox::new();
+    fs::create_dir_all(sb.path("dotfiles")).unwrap();
+    fs::rename(
+        sb.path("configuration.nix"),
+        sb.path("dotfiles/configuration.nix"),
+    )
+    .unwrap();
+    fs::set_permissions(
+        sb.path("dotfiles/configuration.nix"),
+        fs::Permissions::from_mode(0o640),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink(
+        sb.path("dotfiles/configuration.nix"),
+        sb.path("configuration.nix"),
+    )
+    .unwrap();
+    assert!(sb
+        .npkg(&["install", "-S", "-d", "-y", "ripgrep"], &[])
+        .status
+        .success());
+    assert!(fs::symlink_metadata(sb.path("configuration.nix"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(sb.read("dotfiles/configuration.nix").contains("ripgrep"));
+    let mode = fs::metadata(sb.path("dotfiles/configuration.nix"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o640);
+    assert!(!sb.path("dotfiles/.configuration.nix.npkg-tmp").exists());
+}
+
+#[test]
+fn restore_lists_and_restores_backups() {
+    let sb = Sandbox::new();
+    let original = sb.read("configuration.nix");
+    assert!(sb.npkg(&["-iS", "-y", "hello"], &[]).status.success());
+    assert!(sb.npkg(&["-iS", "-y", "cowsay"], &[]).status.success());
+
+    let out = sb.npkg(&["--restore", "-S"], &[]);
+    assert!(out.status.success());
+    let list = String::from_utf8_lossy(&out.stdout).to_string();
+    assert!(list.contains("configuration.nix"));
+
+    let out = sb.npkg(&["--restore", "2", "-S", "-y"], &[]);
+    assert!(out.status.success());
+    assert_eq!(sb.read("configuration.nix"), original);
+    assert_eq!(sb.read("log").matches("nixos-rebuild switch").count(), 3);
+
+    // The restore itself was backed up, so it can be undone
+    assert!(sb
+        .npkg(&["--restore", "1", "-S", "-d", "-y"], &[])
+        .status
+        .success());
+    assert!(sb.read("configuration.nix").contains("cowsay"));
+}
+
+#[test]
+fn restore_unknown_backup_fails() {
+    let sb = Sandbox::new();
+    let original = sb.read("configuration.nix");
+    assert!(!sb.npkg(&["--restore", "1", "-S"], &[]).status.success());
+    assert_eq!(sb.read("configuration.nix"), original);
+}
+
+#[test]
+fn restore_moved_configuration_fails() {
+    let sb = Sandbox::new();
+    assert!(sb.npkg(&["-iS", "-y", "hello"], &[]).status.success());
+    fs::rename(sb.path("configuration.nix"), sb.path("moved.nix")).unwrap();
+    let out = sb.npkg(&["--restore", "1", "-S", "-y"], &[]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Could not read"));
+    assert!(!sb.path("configuration.nix").exists());
+}
+
+#[test]
+fn diff_only_leaves_configuration() {
+    let sb = Sandbox::new();
+    let original = sb.read("configuration.nix");
+    let out = sb.npkg(&["-iS", "--diff-only", "hello"], &[]);
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("+    hello"));
+    assert_eq!(sb.read("configuration.nix"), original);
+    assert!(!sb.read("log").contains("nixos-rebuild"));
+}
+
+#[test]
+fn install_without_confirmation_aborts() {
+    let sb = Sandbox::new();
+    let original = sb.read("configuration.nix");
+    let out = sb.npkg(&["-iS", "hello"], &[]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("--yes"));
+    assert_eq!(sb.read("configuration.nix"), original);
+    assert!(!sb.read("log").contains("nixos-rebuild"));
+}
+
+#[test]
+fn profile_install() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(
+        &["-iP", "hello"],
+        &[("FAKE_STDOUT_NIX", "{\"elements\": {}, \"version\": 3}")],
+    );
+    assert!(out.status.success());
+    assert!(sb.read("log").contains("nix profile install nixpkgs#hello"));
+}
+
+#[test]
+fn profile_remove_by_name_and_index() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(
+        &["-rP", "hello"],
+        &[(
+            "FAKE_STDOUT_NIX",
+            "{\"elements\": {\"hello\": {\"attrPath\": \"legacyPackages.x86_64-linux.hello\"}}, \"version\": 3}",
+        )],
+    );
+    assert!(out.status.success());
+    assert!(sb.read("log").contains("nix profile remove hello"));
+
+    let out = sb.npkg(
+        &["-rP", "cowsay"],
+        &[(
+            "FAKE_STDOUT_NIX",
+            "{\"elements\": [{\"attrPath\": \"legacyPackages.x86_64-linux.hello\"}, {\"attrPath\": \"legacyPackages.x86_64-linux.cowsay\"}], \"version\": 2}",
+        )],
+    );
+    assert!(out.status.success());
+    assert!(sb.read("log").contains("nix profile remove 1"));
+}
+
+#[test]
+fn profile_update_skips_channels() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-uP"], &[]);
+    assert!(out.status.success());
+    let log = sb.read("log");
+    assert!(log.contains("nix profile upgrade --all"));
+    assert!(!log.contains("nix-channel"));
+}
+
+#[test]
+fn profile_selected_by_config() {
+    let sb = Sandbox::new();
+    sb.config("\"nixprofile\": true");
+    let out = sb.npkg(
+        &["-i", "hello"],
+        &[("FAKE_STDOUT_NIX", "{\"elements\": [], \"version\": 2}")],
+    );
+    assert!(out.status.success());
+    assert!(sb.read("log").contains("nix profile install nixpkgs#hello"));
+}
+
+const NIX_ENV_EMPTY: (&str, &str) = ("FAKE_STDOUT_NIX_ENV", "{}");
+
+#[test]
+fn env_install_uses_detected_channel() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkg(&["-i", "hello"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    assert!(sb.read("log").contains("nix-env -iA nixos.hello"));
+
+    let out = sb.npkg(
+        &["-i", "hello"],
+        &[
+            NIX_ENV_EMPTY,
+            (
+                "FAKE_STDOUT_NIX_CHANNEL",
+                "nixpkgs https://nixos.org/channels/nixpkgs-unstable\n",
+            ),
+        ],
+    );
+    assert!(out.status.success());
+    assert!(sb.read("log").contains("nix-env -iA nixpkgs.hello"));
+}
+
+#[test]
+fn env_install_channel_from_cli_and_config() {
+    let sb = Sandbox::new();
+    sb.cache();
+    sb.config("\"channel\": \"nixpkgs\"");
+    assert!(sb.npkg(&["-i", "hello"], &[NIX_ENV_EMPTY]).status.success());
+    assert!(sb
+        .npkg(&["-i", "-c", "unstable", "cowsay"], &[NIX_ENV_EMPTY])
+        .status
+        .success());
+    assert!(sb
+        .npkg(&["-i", "unstable:git", "vim"], &[NIX_ENV_EMPTY])
+        .status
+        .success());
+    let log = sb.read("log");
+    assert!(log.contains("nix-env -iA nixpkgs.hello"));
+    assert!(log.contains("nix-env -iA unstable.cowsay"));
+    assert!(log.contains("nix-env -iA unstable.git nixpkgs.vim"));
+}
+
+#[test]
+fn attr_install_list_and_remove() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-iS", "-y", "-a", "fonts.fonts", "dejavu_fonts"], &[]);
+    assert!(out.status.success());
+    let cfg = sb.read("configuration.nix");
+    assert_eq!(
+        nix_editor::read::getarrvals(&cfg, "fonts.fonts").unwrap(),
+        vec!["noto-fonts", "dejavu_fonts"]
+    );
+    assert_eq!(
+        nix_editor::read::getarrvals(&cfg, "environment.systemPackages").unwrap(),
+        vec!["vim", "git"]
+    );
+
+    let out = sb.npkg(&["-lS", "-a", "users.users.alice.packages"], &[]);
+    assert!(out.status.success());
+    let list = String::from_utf8_lossy(&out.stdout).to_string();
+    assert!(list.contains("users.users.alice.packages"));
+    assert!(list.contains("firefox"));
+    assert!(!list.contains("vim"));
+
+    let out = sb.npkg(
+        &[
+            "-rS",
+            "-y",
+            "--attr",
+            "users.users.alice.packages",
+            "firefox",
+        ],
+        &[],
+    );
+    assert!(out.status.success());
+    assert!(!sb.read("configuration.nix").contains("firefox"));
+}
+
+#[test]
+fn attr_needs_system_or_home() {
+    let sb = Sandbox::new();
+    let original = sb.read("configuration.nix");
+    let out = sb.npkg(&["-i", "-y", "-a", "fonts.fonts", "dejavu_fonts"], &[]);
+    assert!(!out.status.success());
+    assert_eq!(sb.read("configuration.nix"), original);
+}
+
+#[test]
+fn list_shows_imported_files() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-lS"], &[]);
+    assert!(out.status.success());
+    let list = String::from_utf8_lossy(&out.stdout).to_string();
+    assert!(list.contains("htop"));
+    assert!(list.contains("(packages.nix)"));
+    assert!(list.contains("(configuration.nix)"));
+}
+
+#[test]
+fn install_skips_packages_in_imported_files() {
+    let sb = Sandbox::new();
+    let original = sb.read("configuration.nix");
+    let out = sb.npkg(&["-iS", "-y", "htop"], &[]);
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("No new packages"));
+    assert_eq!(sb.read("configuration.nix"), original);
+}
+
+#[test]
+fn remove_from_declaring_files() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-rS", "-y", "htop", "git"], &[]);
+    assert!(out.status.success());
+    let cfg = sb.read("configuration.nix");
+    let pkgs = sb.read("packages.nix");
+    assert!(!pkgs.contains("htop"));
+    assert!(!pkgs.contains("git"));
+    assert!(!cfg.contains("git"));
+    assert!(cfg.contains("vim"));
+    assert_eq!(sb.read("log").matches("nixos-rebuild switch").count(), 1);
+
+    // Backups are restored to the file they were taken from
+    assert!(sb
+        .npkg(&["--restore", "1", "-S", "-d", "-y"], &[])
+        .status
+        .success());
+    assert!(sb.read("packages.nix").contains("htop"));
+    assert_eq!(sb.read("configuration.nix"), cfg);
+}
+
+#[test]
+fn install_into_target_file() {
+    let sb = Sandbox::new();
+    sb.config(&format!(
+        "\"systemtarget\": \"{}\"",
+        sb.path("packages.nix").display()
+    ));
+    let original = sb.read("configuration.nix");
+    let out = sb.npkg(&["-iS", "-y", "hello"], &[]);
+    assert!(out.status.success());
+    assert!(sb.read("packages.nix").contains("hello"));
+    assert_eq!(sb.read("configuration.nix"), original);
+}
+
+fn json(out: &Output) -> serde_json::Value {
+    serde_json::from_slice(&out.stdout).unwrap()
+}
+
+#[test]
+fn json_list() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkg(&["-l", "--json"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    let list = json(&out);
+    let list = list.as_array().unwrap();
+    let htop = list.iter().find(|x| x["package"] == "htop").unwrap();
+    assert_eq!(htop["source"], "system");
+    assert!(htop["file"].as_str().unwrap().ends_with("packages.nix"));
+    let firefox = list
+        .iter()
+        .find(|x| x["package"] == "firefox" && x["source"] == "home")
+        .unwrap();
+    assert!(firefox["file"].as_str().unwrap().ends_with("home.nix"));
+}
+
+#[test]
+fn json_search() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkg(&["-s", "--json", "hello"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    let results = json(&out);
+    let hello = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["pname"] == "hello")
+        .unwrap();
+    assert!(hello["version"].is_string());
+    assert!(hello["description"].is_string());
+    assert_eq!(hello["installed"], serde_json::json!([]));
+}
+
+#[test]
+fn json_install_reports_changes() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["-iS", "-y", "--json", "hello", "vim"], &[]);
+    assert!(out.status.success());
+    let res = json(&out);
+    assert_eq!(res["action"], "install");
+    assert_eq!(res["source"], "system");
+    assert_eq!(res["changed"], serde_json::json!(["hello"]));
+    assert_eq!(res["skipped"], serde_json::json!(["vim"]));
+    assert!(res["files"][0]
+        .as_str()
+        .unwrap()
+        .ends_with("configuration.nix"));
+    assert_eq!(res["status"], 0);
+}
+
+#[test]
+fn json_reports_errors() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(
+        &["-rH", "-y", "--json", "firefox"],
+        &[("FAKE_EXIT_HOME_MANAGER", "1")],
+    );
+    assert!(!out.status.success());
+    let res = json(&out);
+    assert_eq!(res["status"], 1);
+    assert!(res["error"].is_string());
+    assert_eq!(out.status.code(), Some(1));
+
+    // The exit code matches the reported status without an operation too
+    let out = sb.npkg(&["--json"], &[]);
+    assert_eq!(json(&out)["status"], 1);
+    assert_eq!(out.status.code(), Some(1));
+}
+
+#[test]
+fn search_limit() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkg(&["-s", "--json", "--limit", "2", "git"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    let results = json(&out);
+    let names = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["pname"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["git", "git-crypt"]);
+    assert!(results[0]["score"].as_u64().unwrap() > results[1]["score"].as_u64().unwrap());
+}
+
+#[test]
+fn search_filters() {
+    let sb = Sandbox::new();
+    sb.cache();
+    // An index in an older format is rebuilt
+    fs::write(sb.path(".cache/npkg/packages.idx"), "NPKGIDX1").unwrap();
+    let out = sb.npkg(
+        &[
+            "-s",
+            "--json",
+            "git",
+            "license:gpl",
+            "--",
+            "-platform:darwin",
+        ],
+        &[NIX_ENV_EMPTY],
+    );
+    assert!(out.status.success());
+    let results = json(&out);
+    assert_eq!(results.as_array().unwrap().len(), 1);
+    assert_eq!(results[0]["pname"], "gitFull");
+
+    let out = sb.npkg(
+        &["-s", "--json", "git", "!desc:encryption"],
+        &[NIX_ENV_EMPTY],
+    );
+    assert!(out.status.success());
+    let names = json(&out)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["pname"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["git", "gitFull", "legit", "steam"]);
+
+    let out = sb.npkg(&["-s", "/git(/"], &[NIX_ENV_EMPTY]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("invalid regex '/git(/'"));
+}
+
+#[test]
+fn search_highlights_terms() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkg(&["search", "CONTROL", "name:git-2"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("Distributed version \u{1b}[32mcontrol\u{1b}[39m system"));
+    // Filters are not highlighted
+    assert!(!stdout.contains("\u{1b}[32mgit"));
+
+    let out = sb.npkg(
+        &["search", "python", "--", "-name:python2"],
+        &[NIX_ENV_EMPTY],
+    );
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("HTTP library for \u{1b}[32mPython\u{1b}[39m"));
+    assert!(!stdout.contains("python2"));
+}
+
+#[test]
+fn info_card() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkg(&["--info", "git"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("Distributed version control system"));
+    assert!(stdout.contains("GPL-2.0-only (GNU General Public License v2.0 only)"));
+    assert!(stdout.contains("Matthew Bauer (@matthewbauer)"));
+    assert!(stdout.contains("configuration.nix"));
+    assert!(stdout.contains("packages.nix"));
+
+    let out = sb.npkg(&["--info", "--json", "hello", "steam"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    let res = json(&out);
+    assert_eq!(res[0]["attr"], "hello");
+    assert_eq!(
+        res[0]["homepage"][0],
+        "https://www.gnu.org/software/hello/manual/"
+    );
+    assert_eq!(res[0]["licenses"][0]["spdx_id"], "GPL-3.0-or-later");
+    assert_eq!(res[0]["maintainers"][0]["github"], "edolstra");
+    assert_eq!(res[0]["outputs"], serde_json::json!(["out"]));
+    assert_eq!(
+        res[0]["position"],
+        "pkgs/applications/misc/hello/default.nix:34"
+    );
+    assert_eq!(res[0]["installed"], serde_json::json!([]));
+    assert_eq!(res[1]["unfree"], true);
+    assert_eq!(res[1]["broken"], false);
+}
+
+#[test]
+fn info_unknown_package() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkg(&["--info", "hello", "helo"], &[NIX_ENV_EMPTY]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("package not found: helo"));
+}
+
+#[test]
+fn search_hides_broken() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let names = |out: &Output| {
+        json(out)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["pname"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    let out = sb.npkg(&["-s", "--json", "tool"], &[NIX_ENV_EMPTY]);
+    assert!(!names(&out).contains(&"oldtool".to_string()));
+
+    let out = sb.npkg(&["-s", "--json", "--show-broken", "tool"], &[NIX_ENV_EMPTY]);
+    assert!(names(&out).contains(&"oldtool".to_string()));
+    let oldtool = json(&out)
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["pname"] == "oldtool")
+        .cloned()
+        .unwrap();
+    assert_eq!(oldtool["broken"], true);
+
+    let out = sb.npkg(&["-s", "steam"], &[NIX_ENV_EMPTY]);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("unfree"));
+}
+
+#[test]
+fn install_unfree_needs_confirmation() {
+    let sb = Sandbox::new();
+    sb.cache();
+    // Builds the search index
+    assert!(sb
+        .npkg(&["--info", "steam"], &[NIX_ENV_EMPTY])
+        .status
+        .success());
+    let original = sb.read("configuration.nix");
+
+    let out = sb.npkg(&["-iS", "-d", "steam"], &[]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("nixpkgs.config.allowUnfree is not set"));
+    assert_eq!(sb.read("configuration.nix"), original);
+
+    let out = sb.npkg(&["-iS", "-d", "-y", "steam"], &[]);
+    assert!(out.status.success());
+    assert!(sb.read("configuration.nix").contains("steam"));
+
+    // No warning once unfree packages are allowed in an imported file
+    fs::write(
+        sb.path("packages.nix"),
+        sb.read("packages.nix").replace(
+            "environment.systemPackages",
+            "nixpkgs.config.allowUnfree = true;\n  environment.systemPackages",
+        ),
+    )
+    .unwrap();
+    let out = sb.npkg(&["-iS", "-d", "-y", "steam", "hello"], &[]);
+    assert!(out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("allowUnfree"));
+}
+
+#[test]
+fn cache_status_follows_nixpkgs_version() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkg(&["--cache-status", "--json"], &[]);
+    assert!(out.status.success());
+    let status = json(&out);
+    assert_eq!(status["source"], "<nixpkgs>");
+    assert_eq!(status["cached"], NIXPKGS_VERSION);
+    assert_eq!(status["uptodate"], true);
+    assert!(sb
+        .read("log")
+        .contains("nix-instantiate <nixpkgs/lib> -A version"));
+
+    // A channel update changes the revision without a rebuild
+    let out = sb.npkg(
+        &["--cache-status", "--json"],
+        &[("FAKE_NIXPKGS_VERSION", "22.11.20221215.123456")],
+    );
+    let status = json(&out);
+    assert_eq!(status["current"], "22.11.20221215.123456");
+    assert_eq!(status["uptodate"], false);
+
+    let out = sb.npkg(&["--cache-status"], &[]);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("up to date"));
+}
+
+#[test]
+fn cache_status_flake_input() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let cfg = sb.path(".config/npkg/config.json");
+    let flake = sb
+        .read(".config/npkg/config.json")
+        .replace("\"flake\": null", "\"flake\": \"/etc/nixos#host\"");
+    fs::write(cfg, flake).unwrap();
+    let out = sb.npkg(
+        &["--cache-status", "--json"],
+        &[("FAKE_STDOUT_NIX", "\"22.11.20221201.abcdef\"")],
+    );
+    let status = json(&out);
+    assert_eq!(status["source"], "flake:/etc/nixos");
+    // The cache was built from a channel
+    assert_eq!(status["uptodate"], false);
+    assert!(sb
+        .read("log")
+        .contains("nix eval --impure --json --expr (builtins.getFlake \"/etc/nixos\").inputs.\"nixpkgs\".lib.version"));
+
+    // Paths are escaped inside the Nix string
+    let flake = sb.read(".config/npkg/config.json").replace(
+        "\"flake\": \"/etc/nixos#host\"",
+        "\"flake\": \"/home/me/\\\"${x}\\\"#host\"",
+    );
+    fs::write(sb.path(".config/npkg/config.json"), flake).unwrap();
+    sb.npkg(
+        &["--cache-status", "--json"],
+        &[("FAKE_STDOUT_NIX", "\"22.11.20221201.abcdef\"")],
+    );
+    assert!(sb.read("log").contains(
+        "(builtins.getFlake \"/home/me/\\\"\\${x}\\\"\").inputs.\"nixpkgs\".lib.version"
+    ));
+}
+
+#[test]
+fn search_without_nixpkgs_version() {
+    let sb = Sandbox::new();
+    let failing = ("FAKE_EXIT_NIX_INSTANTIATE", "1");
+    let out = sb.npkg(&["-s", "hello"], &[failing, NIX_ENV_EMPTY]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("could not evaluate the nixpkgs version"));
+
+    // An existing cache is still used
+    sb.cache();
+    let out = sb.npkg(&["-s", "--json", "hello"], &[failing, NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    assert_eq!(json(&out)[0]["pname"], "hello");
+}
+
+#[test]
+fn cache_built_with_nix_env() {
+    let sb = Sandbox::new();
+    sb.cache();
+    sb.config("\"cache\": \"nix-env\"");
+    // The cache was downloaded, so it has to be built again
+    let status = json(&sb.npkg(&["--cache-status", "--json"], &[]));
+    assert_eq!(status["builder"], "nix-env");
+    assert_eq!(status["uptodate"], false);
+
+    let packages = fs::read_to_string("tests/fixtures/nix-env.json").unwrap();
+    let out = sb.npkg(&["--refresh-cache"], &[("FAKE_STDOUT_NIX_ENV", &packages)]);
+    assert!(out.status.success());
+    assert!(sb
+        .read("log")
+        .contains("nix-env -f <nixpkgs> -qaP --json --meta"));
+    assert!(!sb.path(".cache/npkg/packages.json.tmp").exists());
+
+    let out = sb.npkg(&["--info", "--json", "my-tool"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    let res = json(&out);
+    assert_eq!(res[0]["description"], "A tool added by a local overlay");
+    assert_eq!(res[0]["licenses"][0]["spdx_id"], "MIT");
+    // Packages that are not in the local nixpkgs are gone
+    let out = sb.npkg(&["--info", "steam"], &[NIX_ENV_EMPTY]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn cache_built_with_nix_search() {
+    let sb = Sandbox::new();
+    sb.config("\"cache\": \"nix-search\"");
+    let packages = fs::read_to_string("tests/fixtures/nix-search.json").unwrap();
+    let out = sb.npkg(
+        &["-s", "--json", "requests"],
+        &[("FAKE_STDOUT_NIX", &packages), NIX_ENV_EMPTY],
+    );
+    assert!(out.status.success());
+    assert!(sb
+        .read("log")
+        .contains("nix search --json --file <nixpkgs>  ^"));
+    let res = json(&out);
+    assert_eq!(res[0]["pname"], "python3Packages.requests");
+    assert_eq!(res[0]["version"], "2.28.1");
+    assert_eq!(res[0]["description"], "HTTP library for Python");
+}
+
+#[test]
+fn cache_builder_failure() {
+    let sb = Sandbox::new();
+    sb.config("\"cache\": \"nix-env\"");
+    let out = sb.npkg(
+        &["--refresh-cache"],
+        &[
+            ("FAKE_EXIT_NIX_ENV", "1"),
+            (
+                "FAKE_STDERR_NIX_ENV",
+                "error: file 'nixpkgs' was not found\\n",
+            ),
+        ],
+    );
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("file 'nixpkgs' was not found"));
+    assert!(!sb.path(".cache/npkg/packages.json").exists());
+    assert!(!sb.path(".cache/npkg/packages.json.tmp").exists());
+}
+
+#[test]
+fn cache_from_local_mirror() {
+    let sb = Sandbox::new();
+    let mirror = sb.path("mirror/22.11/nixos-22.11.20221201.abcdef");
+    fs::create_dir_all(&mirror).unwrap();
+    let mut gz = flate2::write::GzEncoder::new(
+        fs::File::create(mirror.join("packages.json.gz")).unwrap(),
+        flate2::Compression::default(),
+    );
+    gz.write_all(&fs::read("tests/fixtures/packages.json").unwrap())
+        .unwrap();
+    gz.finish().unwrap();
+    sb.config(&format!(
+        "\"packagesurl\": \"file://{}/mirror/{{release}}/nixos-{{version}}/packages.json.gz\"",
+        sb.path("").display()
+    ));
+
+    // Codenames and custom suffixes are not part of the release URL
+    let out = sb.npkg(
+        &["-s", "--json", "greeting"],
+        &[
+            ("FAKE_NIXPKGS_VERSION", "22.11.20221201.abcdef-dirty"),
+            NIX_ENV_EMPTY,
+        ],
+    );
+    assert!(out.status.success());
+    assert_eq!(json(&out)[0]["pname"], "hello");
+    assert!(!sb.path(".cache/npkg/packages.json.tmp").exists());
+
+    let out = sb.npkg(
+        &["--refresh-cache"],
+        &[("FAKE_NIXPKGS_VERSION", "not a version")],
+    );
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Could not parse nixpkgs version"));
+}
+
+#[test]
+fn search_other_sources() {
+    let sb = Sandbox::new();
+    sb.cache();
+    sb.config(&format!(
+        "\"sources\": [\"nixos-unstable\"], \"packagesurl\": \"{}/tests/fixtures/unstable.json\"",
+        env!("CARGO_MANIFEST_DIR")
+    ));
+    let out = sb.npkg(&["-s", "--json", "greet"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    assert!(sb
+        .read("log")
+        .contains("nix-instantiate <nixos-unstable/lib>"));
+    assert!(sb
+        .path(".cache/npkg/sources/nixos-unstable/packages.idx")
+        .exists());
+    let res = json(&out);
+    let hello = res
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["pname"] == "hello")
+        .unwrap();
+    assert_eq!(hello["version"], "2.12.1");
+    assert_eq!(hello["sources"][0]["source"], "nixpkgs");
+    assert_eq!(hello["sources"][1]["source"], "nixos-unstable");
+    assert_eq!(hello["sources"][1]["version"], "2.12.2");
+    // Packages only in unstable are found too
+    let greetd = res
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["pname"] == "greetd-tui")
+        .unwrap();
+    assert_eq!(greetd["sources"].as_array().unwrap().len(), 1);
+
+    let out = sb.npkg(&["-s", "hello"], &[NIX_ENV_EMPTY]);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("available in"));
+    assert!(stdout.contains("2.12.2"));
+
+    let status = json(&sb.npkg(&["--cache-status", "--json"], &[]));
+    assert_eq!(status["name"], "nixpkgs");
+    assert_eq!(status["sources"][0]["name"], "nixos-unstable");
+    assert_eq!(status["sources"][0]["source"], "<nixos-unstable>");
+    assert_eq!(status["sources"][0]["uptodate"], true);
+}
+
+#[test]
+fn search_skips_failing_sources() {
+    let sb = Sandbox::new();
+    sb.cache();
+    sb.config("\"sources\": [\"nixos-unstable\", \"flake:nixpkgs-unstable\"]");
+    let out = sb.npkg(
+        &["-s", "--json", "hello"],
+        &[("FAKE_EXIT_NIX_INSTANTIATE", "1"), NIX_ENV_EMPTY],
+    );
+    assert!(out.status.success());
+    let res = json(&out);
+    assert_eq!(res[0]["pname"], "hello");
+    assert_eq!(res[0]["sources"].as_array().unwrap().len(), 1);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("could not search nixos-unstable"));
+    assert!(stderr.contains("no flake is configured"));
+}
+
+#[test]
+fn nur_source() {
+    let sb = Sandbox::new();
+    sb.config(&format!(
+        "\"sources\": [\"nur\"], \"packagesurl\": \"{}/tests/fixtures/packages.json\"",
+        env!("CARGO_MANIFEST_DIR")
+    ));
+    let nur = "{\"repos.alice.hello-nur\": {\"name\": \"hello-nur-1.0\", \"pname\": \"hello-nur\", \"version\": \"1.0\", \"meta\": {\"description\": \"A greeting from NUR\"}}}";
+    let out = sb.npkg(&["--refresh-cache"], &[("FAKE_STDOUT_NIX_ENV", nur)]);
+    assert!(out.status.success());
+    let log = sb.read("log");
+    assert!(log.contains("nix-instantiate --eval --json --expr builtins.fetchTarball"));
+    assert!(log.contains("--arg pkgs import <nixpkgs> {} -A repos"));
+
+    let out = sb.npkg(&["-s", "--json", "greeting"], &[NIX_ENV_EMPTY]);
+    let res = json(&out);
+    let nur = res
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["pname"] == "repos.alice.hello-nur")
+        .unwrap();
+    assert_eq!(nur["sources"][0]["source"], "nur");
+    assert_eq!(nur["sources"][0]["version"], "1.0");
+}
+
+#[test]
+fn install_checks_attributes() {
+    let sb = Sandbox::new();
+    sb.cache();
+    // Builds the search index
+    assert!(sb
+        .npkg(&["--info", "hello"], &[NIX_ENV_EMPTY])
+        .status
+        .success());
+    let original = sb.read("configuration.nix");
+
+    let out = sb.npkg(
+        &["-iS", "-d", "-y", "helo", "python3Packages.reqests"],
+        &[("FAKE_STDOUT_NIX", "false"), NIX_ENV_EMPTY],
+    );
+    assert!(!out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("unknown package 'helo', did you mean 'hello'?"));
+    assert!(stdout.contains(
+        "unknown package 'python3Packages.reqests', did you mean 'python3Packages.requests'?"
+    ));
+    assert_eq!(sb.read("configuration.nix"), original);
+    let log = sb.read("log");
+    assert!(log.contains(
+        "nix eval --impure --json --expr (import <nixpkgs> {}) ? \"python3Packages\".\"reqests\""
+    ));
+    // Each package is evaluated once
+    assert_eq!(log.matches("nix eval").count(), 2);
+
+    // Nested attributes in the cache are not evaluated
+    fs::remove_file(sb.path("log")).unwrap();
+    let out = sb.npkg(
+        &[
+            "-iS",
+            "-d",
+            "-y",
+            "python3Packages.requests",
+            "nodePackages.prettier",
+        ],
+        &[],
+    );
+    assert!(out.status.success());
+    assert!(sb
+        .read("configuration.nix")
+        .contains("python3Packages.requests"));
+    assert!(!sb.read("log").contains("nix eval"));
+
+    // Packages missing from the cache, e.g. from an overlay, are evaluated
+    let out = sb.npkg(
+        &["-iS", "-d", "-y", "my-overlay-tool"],
+        &[("FAKE_STDOUT_NIX", "true")],
+    );
+    assert!(out.status.success());
+    assert!(sb.read("configuration.nix").contains("my-overlay-tool"));
+}
+
+#[test]
+fn install_checks_attributes_in_channel() {
+    let sb = Sandbox::new();
+    // `helo` is not in the main nixpkgs, only in the channel it is installed from
+    let out = sb.npkg(
+        &[
+            "install",
+            "-E",
+            "-c",
+            "nixos-unstable",
+            "helo",
+            "nixos-23.05:hello",
+        ],
+        &[("FAKE_STDOUT_NIX", "true"), NIX_ENV_EMPTY],
+    );
+    assert!(out.status.success());
+    let log = sb.read("log");
+    assert!(log.contains("nix eval --impure --json --expr (import <nixos-unstable> {}) ? \"helo\""));
+    assert!(log.contains("nix eval --impure --json --expr (import <nixos-23.05> {}) ? \"hello\""));
+    assert!(!log.contains("<nixpkgs>"));
+    assert!(log.contains("nix-env -iA nixos-unstable.helo nixos-23.05.hello"));
+
+    // Unknown packages are refused without a search index too
+    fs::remove_file(sb.path("log")).unwrap();
+    let out = sb.npkg(
+        &["install", "-E", "-c", "nixos-unstable", "helo"],
+        &[("FAKE_STDOUT_NIX", "false"), NIX_ENV_EMPTY],
+    );
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("unknown package 'helo'"));
+    assert!(!sb.read("log").contains("nix-env -iA"));
+}
+
+#[test]
+fn install_lists_candidates_without_terminal() {
+    let sb = Sandbox::new();
+    sb.cache();
+    // Builds the search index
+    assert!(sb
+        .npkg(&["--info", "hello"], &[NIX_ENV_EMPTY])
+        .status
+        .success());
+    let original = sb.read("configuration.nix");
+
+    let out = sb.npkg(
+        &["-iS", "-d", "-y", "--json", "helo"],
+        &[("FAKE_STDOUT_NIX", "false"), NIX_ENV_EMPTY],
+    );
+    assert!(!out.status.success());
+    assert_eq!(
+        json(&out)["error"],
+        "unknown package 'helo', did you mean 'hello'?"
+    );
+    assert_eq!(sb.read("configuration.nix"), original);
+
+    let out = sb.npkg(
+        &["-iE", "python"],
+        &[("FAKE_STDOUT_NIX", "false"), NIX_ENV_EMPTY],
+    );
+    assert!(!out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains(
+        "unknown package 'python', did you mean 'python3Packages.requests' or 'python2'?"
+    ));
+    assert!(!sb.read("log").contains("nix-env -iA"));
+}
+
+#[test]
+fn find_installs_selection() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let args = ["-fS", "-d", "-y", "--limit", "3", "git"];
+    let out = sb.npkginput(&args, &[NIX_ENV_EMPTY], "2-3\n");
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("system"));
+    let cfg = sb.read("configuration.nix");
+    assert!(cfg.contains("git-crypt"));
+    assert!(cfg.contains("gitFull"));
+
+    // Only excluding picks everything else
+    let sb = Sandbox::new();
+    sb.cache();
+    let out = sb.npkginput(&args, &[NIX_ENV_EMPTY], "^2\n");
+    assert!(out.status.success());
+    let cfg = sb.read("configuration.nix");
+    assert!(!cfg.contains("git-crypt"));
+    assert!(cfg.contains("gitFull"));
+}
+
+#[test]
+fn find_rejects_invalid_selection() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let original = sb.read("configuration.nix");
+    let args = ["-fS", "-d", "-y", "--limit", "3", "git"];
+
+    let out = sb.npkginput(&args, &[NIX_ENV_EMPTY], "4\n");
+    assert!(!out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("4 is not between 1 and 3"));
+    assert!(stdout.contains("Aborted"));
+
+    let out = sb.npkginput(&args, &[NIX_ENV_EMPTY], "\n");
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Nothing to install"));
+    assert_eq!(sb.read("configuration.nix"), original);
+}
+
+#[test]
+fn subcommands_install_and_remove() {
+    let sb = Sandbox::new();
+    let out = sb.npkg(&["install", "-S", "-y", "hello"], &[]);
+    assert!(out.status.success());
+    assert!(sb.read("configuration.nix").contains("hello"));
+    assert!(sb
+        .read("log")
+        .contains("nixos-rebuild switch --use-remote-sudo"));
+
+    let out = sb.npkg(&["rm", "-S", "-y", "--json", "hello"], &[]);
+    assert!(out.status.success());
+    assert_eq!(json(&out)["changed"], serde_json::json!(["hello"]));
+    assert!(!sb.read("configuration.nix").contains("hello"));
+
+    let out = sb.npkg(&["i", "-E", "hello"], &[NIX_ENV_EMPTY]);
+    assert!(out.status.success());
+    assert!(sb.read("log").contains("nix-env -iA nixos.hello"));
+}
+
+#[test]
+fn subcommands_match_legacy_flags() {
+    let sb = Sandbox::new();
+    sb.cache();
+    for (new, old) in [
+        (vec!["list", "--json"], vec!["-l", "--json"]),
+        (vec!["ls", "-S", "--json"], vec!["-lS", "--json"]),
+        (
+            vec!["search", "--json", "--limit", "2", "git"],
+            vec!["-s", "--json", "--limit", "2", "git"],
+        ),
+        (
+            vec!["info", "--json", "hello"],
+            vec!["--info", "--json", "hello"],
+        ),
+    ] {
+        let new = sb.npkg(&new, &[NIX_ENV_EMPTY]);
+        let old = sb.npkg(&old, &[NIX_ENV_EMPTY]);
+        assert!(new.status.success());
+        assert_eq!(json(&new), json(&old));
+    }
+
+    let out = sb.npkg(&["cache", "--json"], &[]);
+    assert_eq!(json(&out)["cached"], NIXPKGS_VERSION);
+
+    let out = sb.npkg(&["update", "-P"], &[]);
+    assert!(out.status.success());
+    let log = sb.read("log");
+    assert!(log.contains("nix profile upgrade --all"));
+    assert!(!log.contains("nix-channel"));
+}
+
+#[test]
+fn global_flags_before_subcommand() {
+    let sb = Sandbox::new();
+    sb.cache();
+    let before = sb.npkg(&["--json", "list"], &[NIX_ENV_EMPTY]);
+    let after = sb.npkg(&["list", "--json"], &[NIX_ENV_EMPTY]);
+    assert!(before.status.success());
+    assert_eq!(json(&before), json(&after));
+
+    sb.config("\"cache\": \"nix-env\"");
+    let packages = fs::read_to_string("tests/fixtures/nix-env.json").unwrap();
+    let out = sb.npkg(
+        &["--refresh-cache", "--json", "search", "my-tool"],
+        &[("FAKE_STDOUT_NIX_ENV", &packages)],
+    );
+    assert!(out.status.success());
+    assert!(sb
+        .read("log")
+        .contains("nix-env -f <nixpkgs> -qaP --json --meta"));
+    assert_eq!(json(&out)[0]["pname"], "my-tool");
+
+    // Other options still belong after the subcommand
+    let out = sb.npkg(&["--dry-run", "list"], &[]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("have to be given after it"));
+}
+
+#[test]
+fn subcommands_check_options() {
+    let sb = Sandbox::new();
+    // Options only apply to the commands they belong to
+    for args in [
+        vec!["search", "--attr", "fonts.fonts", "hello"],
+        vec!["install", "-E", "-o", "out.nix", "hello"],
+        vec!["install"],
+        vec!["list", "-S", "-H"],
+        vec!["-S", "install", "hello"],
+    ] {
+        let out = sb.npkg(&args, &[]);
+        assert!(!out.status.success(), "{:?} succeeded", args);
+    }
+    assert!(!sb.read("log").contains("nixos-rebuild"));
+
+    let out = sb.npkg(&["install", "-a", "fonts.fonts", "hello"], &[]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout)
+        .contains("--attr can only be used with --system or --home"));
+}
@@ -0,0 +1,423 @@
+use npkg::*;
+use sha2::{Digest, Sha256};
+use std::{
+    cell::RefCell,
+    fs,
+    io::{Read, Write},
+    net::TcpListener,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Requests received by the stand-in server as (path, start of the requested range)
+type Requests = Arc<Mutex<Vec<(String, Option<u64>)>>>;
+
+/// Serves HTTP on a local port, answering request number `n` with the raw bytes `handler` returns
+fn serve<F>(handler: F) -> (String, Requests)
+where
+    F: Fn(usize, &str, Option<u64>) -> Vec<u8> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests: Requests = Arc::new(Mutex::new(vec![]));
+    let log = requests.clone();
+    thread::spawn(move || {
+        for (n, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let mut head = vec![];
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+            let range = head.lines().find_map(|line| {
+                line.strip_prefix("Range: bytes=")
+                    .and_then(|x| x.trim_end_matches('-').parse().ok())
+            });
+            log.lock().unwrap().push((path.clone(), range));
+            let _ = stream.write_all(&handler(n, &path, range));
+        }
+    });
+    (url, requests)
+}
+
+fn response(status: &str, length: usize, body: &[u8]) -> Vec<u8> {
+    let mut out = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, length
+    )
+    .into_bytes();
+    out.extend_from_slice(body);
+    out
+}
+
+fn payload() -> (Vec<u8>, Vec<u8>) {
+    let packages = (0..2000)
+        .map(|i| format!("\"pkg{}\": {{\"version\": \"{}\"}}", i, i * 7919 % 1013))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    let plain = format!("{{\"packages\": {{\n{}\n}}}}\n", packages).into_bytes();
+    let mut compressed = vec![];
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 9, 22);
+        writer.write_all(&plain).unwrap();
+    }
+    (plain, compressed)
+}
+
+fn opts<'a>() -> DownloadOptions<'a> {
+    DownloadOptions {
+        delay: Duration::from_millis(10),
+        ..Default::default()
+    }
+}
+
+fn leftovers(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn download_and_decompress() {
+    let (plain, compressed) = payload();
+    let body = compressed.clone();
+    let (url, _) = serve(move |_, _, _| response("200 OK", body.len(), &body));
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+
+    let seen = RefCell::new(vec![]);
+    let progress = |done, total| seen.borrow_mut().push((done, total));
+    download(
+        &format!("{}/packages.json.br", url),
+        &dest,
+        &DownloadOptions {
+            progress: Some(&progress),
+            ..opts()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(fs::read(&dest).unwrap(), plain);
+    assert_eq!(leftovers(dir.path()), vec!["packages.json"]);
+    let len = compressed.len() as u64;
+    assert_eq!(seen.borrow().last(), Some(&(len, Some(len))));
+}
+
+#[test]
+fn resume_interrupted_download() {
+    let (plain, compressed) = payload();
+    let half = compressed.len() / 2;
+    let body = compressed.clone();
+    let (url, requests) = serve(move |n, _, range| match (n, range) {
+        // The connection drops after half of the data
+        (0, _) => response("200 OK", body.len(), &body[..half]),
+        (_, Some(start)) => response(
+            "206 Partial Content",
+            body.len() - start as usize,
+            &body[start as usize..],
+        ),
+        _ => response("200 OK", body.len(), &body),
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+
+    download(&format!("{}/packages.json.br", url), &dest, &opts()).unwrap();
+
+    assert_eq!(fs::read(&dest).unwrap(), plain);
+    assert_eq!(leftovers(dir.path()), vec!["packages.json"]);
+    let ranges = requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|x| x.1)
+        .collect::<Vec<_>>();
+    assert_eq!(ranges, vec![None, Some(half as u64)]);
+}
+
+#[test]
+fn restart_when_range_is_ignored() {
+    let (plain, compressed) = payload();
+    let body = compressed.clone();
+    let (url, requests) = serve(move |_, _, _| response("200 OK", body.len(), &body));
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+    let url = format!("{}/packages.json.br", url);
+    // Left over from an earlier download of the same URL, which has changed since
+    fs::write(dir.path().join("packages.json.part"), b"stale data").unwrap();
+    fs::write(dir.path().join("packages.json.part.url"), &url).unwrap();
+
+    download(&url, &dest, &opts()).unwrap();
+
+    assert_eq!(fs::read(&dest).unwrap(), plain);
+    assert_eq!(requests.lock().unwrap()[0].1, Some(10));
+    assert_eq!(leftovers(dir.path()), vec!["packages.json"]);
+}
+
+#[test]
+fn restart_download_of_other_url() {
+    let (plain, compressed) = payload();
+    let body = compressed.clone();
+    let (url, requests) = serve(move |_, _, range| {
+        let start = range.unwrap_or(0) as usize;
+        response("206 Partial Content", body.len() - start, &body[start..])
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+    // Interrupted download of another nixpkgs version
+    fs::write(dir.path().join("packages.json.part"), &compressed[..10]).unwrap();
+    fs::write(
+        dir.path().join("packages.json.part.url"),
+        format!("{}/22.05/packages.json.br", url),
+    )
+    .unwrap();
+
+    download(&format!("{}/22.11/packages.json.br", url), &dest, &opts()).unwrap();
+
+    assert_eq!(fs::read(&dest).unwrap(), plain);
+    assert_eq!(requests.lock().unwrap()[0].1, None);
+    assert_eq!(leftovers(dir.path()), vec!["packages.json"]);
+}
+
+#[test]
+fn restart_when_range_is_not_satisfiable() {
+    let (plain, compressed) = payload();
+    let body = compressed.clone();
+    let (url, requests) = serve(move |_, _, range| match range {
+        Some(_) => response("416 Range Not Satisfiable", 0, b""),
+        None => response("200 OK", body.len(), &body),
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+    let url = format!("{}/packages.json.br", url);
+    // Longer than the file on the server
+    let mut stale = compressed.clone();
+    stale.extend_from_slice(b"stale data");
+    fs::write(dir.path().join("packages.json.part"), &stale).unwrap();
+    fs::write(dir.path().join("packages.json.part.url"), &url).unwrap();
+
+    download(&url, &dest, &opts()).unwrap();
+
+    assert_eq!(fs::read(&dest).unwrap(), plain);
+    let ranges = requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|x| x.1)
+        .collect::<Vec<_>>();
+    assert_eq!(ranges, vec![Some(stale.len() as u64), None]);
+    assert_eq!(leftovers(dir.path()), vec!["packages.json"]);
+}
+
+#[test]
+fn retry_server_errors() {
+    let (plain, compressed) = payload();
+    let body = compressed.clone();
+    let (url, requests) = serve(move |n, _, _| match n {
+        0 | 1 => response("503 Service Unavailable", 5, b"busy\n"),
+        _ => response("200 OK", body.len(), &body),
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+
+    download(&format!("{}/packages.json.br", url), &dest, &opts()).unwrap();
+
+    assert_eq!(fs::read(&dest).unwrap(), plain);
+    assert_eq!(requests.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn missing_file_is_not_retried() {
+    let (url, requests) = serve(|_, _, _| response("404 Not Found", 10, b"not found\n"));
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+
+    let err = download(&format!("{}/packages.json.br", url), &dest, &opts()).unwrap_err();
+
+    assert!(matches!(err, DownloadError::HttpError(_, 404)));
+    assert_eq!(requests.lock().unwrap().len(), 1);
+    assert!(!dest.exists());
+}
+
+#[test]
+fn give_up_after_retries() {
+    let (url, requests) = serve(|_, _, _| response("500 Internal Server Error", 0, b""));
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+
+    let err = download(
+        &format!("{}/packages.json.br", url),
+        &dest,
+        &DownloadOptions {
+            retries: 2,
+            ..opts()
+        },
+    )
+    .unwrap_err();
+
+    assert!(matches!(err, DownloadError::HttpError(_, 500)));
+    assert_eq!(requests.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn verify_checksum() {
+    let (plain, compressed) = payload();
+    let hash = Sha256::digest(&compressed)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let body = compressed.clone();
+    let sum = format!("{}  packages.json.br\n", hash);
+    let (url, _) = serve(move |_, path, _| {
+        if path.ends_with(".sha256") {
+            response("200 OK", sum.len(), sum.as_bytes())
+        } else {
+            response("200 OK", body.len(), &body)
+        }
+    });
+    let url = format!("{}/packages.json.br", url);
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+    fs::write(&dest, b"old").unwrap();
+
+    let err = download(
+        &url,
+        &dest,
+        &DownloadOptions {
+            sha256: Some("00".repeat(32)),
+            ..opts()
+        },
+    )
+    .unwrap_err();
+    match err {
+        DownloadError::ChecksumError { expected, actual } => {
+            assert_eq!(expected, "00".repeat(32));
+            assert_eq!(actual, hash);
+        }
+        e => panic!("unexpected error: {}", e),
+    }
+    assert_eq!(fs::read(&dest).unwrap(), b"old");
+    assert_eq!(leftovers(dir.path()), vec!["packages.json"]);
+
+    let sha256 = checksum(&url).unwrap();
+    assert_eq!(sha256.as_deref(), Some(hash.as_str()));
+    download(&url, &dest, &DownloadOptions { sha256, ..opts() }).unwrap();
+    assert_eq!(fs::read(&dest).unwrap(), plain);
+}
+
+#[test]
+fn missing_checksum() {
+    let (url, _) = serve(|_, _, _| response("404 Not Found", 10, b"not found\n"));
+    assert_eq!(
+        checksum(&format!("{}/packages.json.br", url)).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn corrupt_download_keeps_old_file() {
+    let (url, _) = serve(|_, _, _| response("200 OK", 16, b"definitely!brotl"));
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+    fs::write(&dest, b"old").unwrap();
+
+    let err = download(&format!("{}/packages.json.br", url), &dest, &opts()).unwrap_err();
+
+    assert!(matches!(err, DownloadError::DecompressError(_)));
+    assert_eq!(fs::read(&dest).unwrap(), b"old");
+    assert_eq!(leftovers(dir.path()), vec!["packages.json"]);
+}
+
+#[test]
+fn extract_compression_formats() {
+    let (plain, brotli) = payload();
+    let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    gzip.write_all(&plain).unwrap();
+    let gzip = gzip.finish().unwrap();
+    let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+    xz.write_all(&plain).unwrap();
+    let xz = xz.finish().unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+    for (name, data) in [
+        ("list.json", &plain),
+        ("list.json.gz", &gzip),
+        ("list.json.xz", &xz),
+        ("list.json.br", &brotli),
+        // Without a known extension the format is detected from the data
+        ("plain", &plain),
+        ("gzip", &gzip),
+        ("xz", &xz),
+        ("brotli", &brotli),
+    ] {
+        let src = dir.path().join(name);
+        fs::write(&src, data).unwrap();
+        fs::remove_file(&dest).ok();
+        extract(&src, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), plain, "{}", name);
+    }
+    assert!(!dir.path().join("packages.json.tmp").exists());
+}
+
+#[test]
+fn download_detects_compression() {
+    let (plain, _) = payload();
+    let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    gzip.write_all(&plain).unwrap();
+    let body = gzip.finish().unwrap();
+    let (url, _) = serve(move |_, _, _| response("200 OK", body.len(), &body));
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("packages.json");
+
+    download(&format!("{}/latest", url), &dest, &opts()).unwrap();
+
+    assert_eq!(fs::read(&dest).unwrap(), plain);
+}
+
+#[test]
+fn parse_nixos_versions() {
+    let parse = |x: &str| NixosVersion::parse(x).map(|v| (v.release, v.version));
+    let version = |release: &str, version: &str| Some((release.to_string(), version.to_string()));
+    assert_eq!(
+        parse("22.11.1234.abcdef1"),
+        version("22.11", "22.11.1234.abcdef1")
+    );
+    assert_eq!(
+        parse("22.11.4066.abcdef1 (Raccoon)"),
+        version("22.11", "22.11.4066.abcdef1")
+    );
+    assert_eq!(
+        parse("\"23.05pre447497.3a5b2f4\""),
+        version("unstable", "23.05pre447497.3a5b2f4")
+    );
+    assert_eq!(
+        parse("nixpkgs-darwin-22.11.1234.abcdef1"),
+        version("22.11", "22.11.1234.abcdef1")
+    );
+    assert_eq!(
+        parse("22.11.20221201.abcdef1-dirty"),
+        version("22.11", "22.11.20221201.abcdef1")
+    );
+    assert_eq!(parse("23.05pre-git"), version("unstable", "23.05pre"));
+    assert_eq!(parse("9.1"), version("9.1", "9.1"));
+    assert_eq!(parse("not a version"), None);
+    assert_eq!(parse("22"), None);
+    assert_eq!(parse(""), None);
+}
+
+#[test]
+fn unstable_needs_full_version() {
+    // There is no `nixos/unstable/nixos-unstable` release to download
+    assert_eq!(NixosVersion::parse("unstable"), None);
+    assert_eq!(NixosVersion::parse("nixos-unstable"), None);
+    let version = NixosVersion::parse("nixos-23.05pre447497.3a5b2f4").unwrap();
+    assert_eq!(version.release, "unstable");
+    assert_eq!(version.version, "23.05pre447497.3a5b2f4");
+}
@@ -0,0 +1,208 @@
+use npkg::*;
+
+const CONFIG: &str = include_str!("fixtures/configuration.nix");
+const HOME: &str = include_str!("fixtures/home.nix");
+
+#[test]
+fn envinstall_uses_nixos_channel() {
+    let runner = FakeRunner::new();
+    assert!(envinstall(
+        &runner,
+        vec!["hello".to_string(), "git".to_string()],
+        "nixos"
+    )
+    .is_ok());
+    assert_eq!(runner.calls(), vec!["nix-env -iA nixos.hello nixos.git"]);
+}
+
+#[test]
+fn envinstall_channel_prefix() {
+    let runner = FakeRunner::new();
+    assert!(envinstall(
+        &runner,
+        vec!["hello".to_string(), "unstable:git".to_string()],
+        "nixpkgs"
+    )
+    .is_ok());
+    assert_eq!(
+        runner.calls(),
+        vec!["nix-env -iA nixpkgs.hello unstable.git"]
+    );
+}
+
+#[test]
+fn listchannels_names() {
+    let runner = FakeRunner::new();
+    runner.respond(
+        "nix-channel --list",
+        0,
+        "nixpkgs https://nixos.org/channels/nixpkgs-unstable\nhome-manager https://github.com/nix-community/home-manager/archive/master.tar.gz\n",
+        "",
+    );
+    assert_eq!(
+        listchannels(&runner).unwrap(),
+        vec!["nixpkgs", "home-manager"]
+    );
+}
+
+#[test]
+fn envinstall_reports_failure() {
+    let runner = FakeRunner::new();
+    runner.respond(
+        "nix-env -iA",
+        1,
+        "",
+        "error: attribute 'helo' in selection path 'nixos.helo' not found\n",
+    );
+    match envinstall(&runner, vec!["helo".to_string()], "nixos") {
+        Err(ExecuteError::CmdError(e)) => {
+            assert_eq!(e.cmd, "nix-env -iA nixos.helo");
+            assert_eq!(e.code, Some(1));
+            assert!(e.stderr.contains("'nixos.helo' not found"));
+        }
+        _ => panic!("expected a command error"),
+    }
+}
+
+#[test]
+fn envremove_and_envupdate() {
+    let runner = FakeRunner::new();
+    assert!(envremove(&runner, vec!["hello-2.12".to_string()]).is_ok());
+    assert!(envupdate(&runner).is_ok());
+    assert_eq!(
+        runner.calls(),
+        vec!["nix-env -e hello-2.12", "nix-env -u *"]
+    );
+}
+
+#[test]
+fn profile_commands() {
+    let runner = FakeRunner::new();
+    assert!(profileinstall(&runner, vec!["hello".to_string()]).is_ok());
+    assert!(profileremove(&runner, vec!["hello".to_string()]).is_ok());
+    assert!(profileupgrade(&runner).is_ok());
+    assert_eq!(
+        runner.calls(),
+        vec![
+            "nix profile install nixpkgs#hello",
+            "nix profile remove hello",
+            "nix profile upgrade --all"
+        ]
+    );
+}
+
+#[test]
+fn system_install_flow() {
+    let runner = FakeRunner::new();
+    let out = pkwrite(vec!["hello".to_string()], CONFIG, None).unwrap();
+    assert!(out.contains("hello"));
+    assert!(out.contains("vim"));
+    assert!(systemswitch(&runner).is_ok());
+    assert_eq!(
+        runner.calls(),
+        vec!["nixos-rebuild switch --use-remote-sudo"]
+    );
+}
+
+#[test]
+fn system_rebuild_failure_keeps_stderr() {
+    let runner = FakeRunner::new();
+    runner.respond(
+        "nixos-rebuild switch",
+        100,
+        "",
+        "building the system configuration...\nerror: undefined variable 'helo'\n",
+    );
+    match systemflakeswitch(&runner, "/etc/nixos#host") {
+        Err(ExecuteError::CmdError(e)) => {
+            assert_eq!(
+                e.cmd,
+                "nixos-rebuild switch --flake /etc/nixos#host --use-remote-sudo"
+            );
+            assert_eq!(e.code, Some(100));
+            assert!(e.stderr.ends_with("error: undefined variable 'helo'"));
+        }
+        _ => panic!("expected a command error"),
+    }
+}
+
+#[test]
+fn home_remove_flow() {
+    let runner = FakeRunner::new();
+    let out = pkrm(vec!["firefox".to_string()], HOME, Some("home.packages")).unwrap();
+    assert!(!out.contains("firefox"));
+    assert!(out.contains("vim"));
+    assert!(homeswitch(&runner).is_ok());
+    assert!(homeflakeswitch(&runner, "/home/user/nix#user").is_ok());
+    assert_eq!(
+        runner.calls(),
+        vec![
+            "home-manager switch",
+            "home-manager switch --flake /home/user/nix#user"
+        ]
+    );
+}
+
+#[test]
+fn home_switch_failure() {
+    let runner = FakeRunner::new();
+    runner.respond("home-manager", 1, "", "error: collision between files\n");
+    assert!(matches!(
+        homeswitch(&runner),
+        Err(ExecuteError::CmdError(CmdFailure { code: Some(1), .. }))
+    ));
+}
+
+#[test]
+fn update_flow() {
+    let runner = FakeRunner::new();
+    assert!(updatechannel(&runner).is_ok());
+    assert!(updatesyschannel(&runner).is_ok());
+    assert!(updateflake(&runner, "/etc/nixos#host").is_ok());
+    assert_eq!(
+        runner.calls(),
+        vec![
+            "nix-channel --update",
+            "sudo nix-channel --update",
+            "nix flake update /etc/nixos"
+        ]
+    );
+}
+
+#[test]
+fn pkrm_missing_attribute() {
+    match pkrm(vec!["vim".to_string()], HOME, None) {
+        Err(ExecuteError::EditError(EditError::NoAttr(attr, _))) => {
+            assert_eq!(attr, "environment.systemPackages")
+        }
+        _ => panic!("expected a missing attribute error"),
+    }
+}
+
+#[test]
+fn pkwrite_nested_attribute() {
+    let out = pkwrite(
+        vec!["chromium".to_string()],
+        CONFIG,
+        Some("users.users.alice.packages"),
+    )
+    .unwrap();
+    assert_eq!(
+        nix_editor::read::getarrvals(&out, "users.users.alice.packages").unwrap(),
+        vec!["firefox", "chromium"]
+    );
+}
+
+#[test]
+fn pkwrite_new_attribute_uses_pkgs_prefix() {
+    let out = pkwrite(
+        vec!["steam-run".to_string()],
+        CONFIG,
+        Some("programs.steam.extraPackages"),
+    )
+    .unwrap();
+    assert_eq!(
+        nix_editor::read::getarrvals(&out, "programs.steam.extraPackages").unwrap(),
+        vec!["pkgs.steam-run"]
+    );
+}
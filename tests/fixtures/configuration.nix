@@ -0,0 +1,25 @@
+{ config, pkgs, ... }:
+
+{
+  imports = [ ./hardware-configuration.nix ./packages.nix ];
+
+  networking.hostName = "nixos";
+
+  users.users.alice = {
+    isNormalUser = true;
+    packages = with pkgs; [
+      firefox
+    ];
+  };
+
+  fonts.fonts = with pkgs; [
+    noto-fonts
+  ];
+
+  environment.systemPackages = with pkgs; [
+    vim
+    git
+  ];
+
+  system.stateVersion = "22.05";
+}
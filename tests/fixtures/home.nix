@@ -0,0 +1,13 @@
+{ config, pkgs, ... }:
+
+{
+  home.username = "user";
+  home.homeDirectory = "/home/user";
+
+  home.packages = with pkgs; [
+    vim
+    firefox
+  ];
+
+  home.stateVersion = "22.05";
+}
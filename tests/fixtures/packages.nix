@@ -0,0 +1,10 @@
+{ pkgs, ... }:
+
+{
+  imports = [ ./configuration.nix <home-manager/nixos> ];
+
+  environment.systemPackages = with pkgs; [
+    htop
+    git
+  ];
+}
@@ -0,0 +1,274 @@
+use npkg::*;
+
+const PACKAGES: &[u8] = include_bytes!("fixtures/packages.json");
+
+fn index() -> Index {
+    Index::from_bytes(buildindex(PACKAGES).unwrap()).unwrap()
+}
+
+#[test]
+fn search_matches_attribute_and_description() {
+    let index = index();
+    let attrs = |q: &[&str]| {
+        index
+            .search(&q.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+            .iter()
+            .map(|x| x.attr.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        attrs(&["GIT"]),
+        vec!["git", "git-crypt", "gitFull", "legit", "steam"]
+    );
+    assert_eq!(attrs(&["greeting"]), vec!["hello"]);
+    assert_eq!(attrs(&["git", "encryption"]), vec!["git-crypt"]);
+    assert!(attrs(&["nosuchpackage"]).is_empty());
+}
+
+#[test]
+fn lookup_by_attribute_and_name() {
+    let index = index();
+    let hello = index.byattr("hello").unwrap();
+    assert_eq!(hello.name, "hello-2.12.1");
+    assert_eq!(hello.version, "2.12.1");
+    assert_eq!(
+        hello.description,
+        Some("A program that produces a familiar, friendly greeting")
+    );
+    assert_eq!(index.byname("hello-2.12.1").unwrap().attr, "hello");
+    assert!(index.byattr("helo").is_none());
+    assert!(index.byname("hello").is_none());
+}
+
+#[test]
+fn open_memory_mapped_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let json = dir.path().join("packages.json");
+    let idx = dir.path().join("packages.idx");
+    std::fs::write(&json, PACKAGES).unwrap();
+    writeindex(&json, &idx).unwrap();
+    let index = Index::open(&idx).unwrap();
+    assert_eq!(index.len(), self::index().len());
+    assert_eq!(
+        index.byattr("python3Packages.requests").unwrap().attr,
+        "python3Packages.requests"
+    );
+}
+
+#[test]
+fn invalid_index() {
+    assert!(matches!(
+        Index::from_bytes(b"{\"packages\": {}}".to_vec()),
+        Err(IndexError::InvalidIndex)
+    ));
+    let mut data = buildindex(PACKAGES).unwrap();
+    data.truncate(100);
+    assert!(matches!(
+        Index::from_bytes(data),
+        Err(IndexError::InvalidIndex)
+    ));
+    assert!(matches!(
+        buildindex(b"not json"),
+        Err(IndexError::ParseError(_))
+    ));
+}
+
+fn ranked(index: &Index, query: &[&str]) -> Vec<String> {
+    index
+        .rank(&query.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+        .into_iter()
+        .map(|x| x.pname)
+        .collect()
+}
+
+#[test]
+fn rank_exact_and_prefix_first() {
+    let index = index();
+    assert_eq!(
+        ranked(&index, &["git"]),
+        // git-crypt also mentions git in its description
+        vec!["git", "git-crypt", "gitFull", "legit", "steam"]
+    );
+    assert_eq!(ranked(&index, &["requests"])[0], "python3Packages.requests");
+    assert_eq!(ranked(&index, &["git", "encryption"]), vec!["git-crypt"]);
+}
+
+#[test]
+fn rank_matches_typos() {
+    let index = index();
+    assert_eq!(ranked(&index, &["gti"])[0], "git");
+    assert_eq!(ranked(&index, &["firefx"]), vec!["firefox"]);
+    assert!(ranked(&index, &["xyzzy"]).is_empty());
+}
+
+#[test]
+fn score_and_rank() {
+    let q = vec!["hello".to_string()];
+    let exact = score(&q, "hello", None).unwrap();
+    let desc = score(&q, "cowsay", Some("Says hello")).unwrap();
+    assert!(exact > desc);
+    assert_eq!(score(&q, "cowsay", Some("A talking cow")), None);
+
+    let mut pkgs = vec![
+        PkgData {
+            pname: "cowsay".to_string(),
+            description: None,
+            version: "3.04".to_string(),
+            score: desc,
+            ..Default::default()
+        },
+        PkgData {
+            pname: "hello".to_string(),
+            description: None,
+            version: "2.12.1".to_string(),
+            score: exact,
+            ..Default::default()
+        },
+    ];
+    rank(&mut pkgs);
+    assert_eq!(pkgs[0].pname, "hello");
+}
+
+fn queried(index: &Index, query: &[&str]) -> Vec<String> {
+    let query = Query::parse(&query.iter().map(|x| x.to_string()).collect::<Vec<_>>()).unwrap();
+    index.query(&query).into_iter().map(|x| x.pname).collect()
+}
+
+#[test]
+fn query_field_filters() {
+    let index = index();
+    assert_eq!(queried(&index, &["git", "license:gpl3"]), vec!["git-crypt"]);
+    assert_eq!(
+        queried(&index, &["license:MIT"]),
+        vec!["nodePackages.prettier"]
+    );
+    assert_eq!(queried(&index, &["maintainer:rycee"]), vec!["cowsay"]);
+    assert_eq!(queried(&index, &["name:hello-2"]), vec!["hello"]);
+    assert_eq!(queried(&index, &["version:107"]), vec!["firefox"]);
+    assert_eq!(
+        queried(&index, &["platform:darwin", "desc:macos"]),
+        vec!["macos-only"]
+    );
+}
+
+#[test]
+fn query_regex_and_negation() {
+    let index = index();
+    assert_eq!(
+        queried(&index, &["/^python[0-9]/"]),
+        vec!["python2", "python3Packages.requests"]
+    );
+    assert_eq!(
+        queried(&index, &["platform:/^aarch64-darwin$/"]),
+        vec!["git", "hello", "macos-only"]
+    );
+    assert_eq!(
+        queried(&index, &["git", "-desc:encryption", "!steam"]),
+        vec!["git", "gitFull", "legit"]
+    );
+    assert_eq!(
+        queried(&index, &["git", "-platform:darwin"]),
+        vec!["gitFull", "legit", "steam"]
+    );
+}
+
+#[test]
+fn query_errors() {
+    let parse = |q: &str| Query::parse(&[q.to_string()]);
+    assert!(matches!(parse("/git(/"), Err(QueryError::InvalidRegex(..))));
+    match parse("color:red") {
+        Err(QueryError::UnknownField(x)) => assert_eq!(x, "color"),
+        _ => panic!("expected an unknown field error"),
+    }
+    // Only alphabetic prefixes are fields
+    let query = parse("unstable-2:git").unwrap();
+    assert_eq!(query.terms, vec!["unstable-2:git"]);
+}
+
+#[test]
+fn full_metadata() {
+    let index = index();
+    let hello = index.byattr("hello").unwrap().info();
+    assert_eq!(
+        hello.homepage,
+        vec!["https://www.gnu.org/software/hello/manual/"]
+    );
+    assert!(hello.long_description.unwrap().starts_with("GNU Hello"));
+    assert_eq!(
+        hello.licenses,
+        vec![License {
+            spdx_id: Some("GPL-3.0-or-later".to_string()),
+            short_name: Some("gpl3Plus".to_string()),
+            full_name: Some("GNU General Public License v3.0 or later".to_string()),
+        }]
+    );
+    assert_eq!(hello.maintainers[0].github.as_deref(), Some("edolstra"));
+    assert_eq!(hello.outputs, vec!["out"]);
+    assert!(!hello.broken && !hello.insecure && !hello.unfree && !hello.unsupported);
+
+    let legit = index.byattr("legit").unwrap().info();
+    assert_eq!(legit.licenses[0].short_name.as_deref(), Some("bsd3"));
+    assert_eq!(legit.licenses[0].spdx_id, None);
+
+    // Unfree licenses are flagged even without `meta.unfree`
+    assert!(index.byattr("steam").unwrap().unfree);
+    assert!(index.byattr("oldtool").unwrap().broken);
+    let python2 = index.byattr("python2").unwrap();
+    assert!(python2.insecure);
+    assert!(python2.vulnerabilities.is_some());
+}
+
+#[test]
+fn platform_support() {
+    let index = index();
+    let macos = index.byattr("macos-only").unwrap();
+    assert!(macos.supports("aarch64-darwin"));
+    assert!(!macos.supports("x86_64-linux"));
+    // Without a list of platforms the package is assumed to build everywhere
+    let mut pkg = index.byattr("hello").unwrap();
+    pkg.platforms = None;
+    assert!(pkg.supports("riscv64-linux"));
+    // Platforms may also be patterns matching a part of the system
+    let kmod = index.byattr("kmod").unwrap();
+    assert!(kmod.supports("x86_64-linux"));
+    assert!(kmod.supports("riscv64-linux"));
+    assert!(kmod.supports("x86_64-freebsd"));
+    assert!(!kmod.supports("aarch64-freebsd"));
+    assert!(!kmod.supports("x86_64-darwin"));
+    assert_eq!(kmod.info().platforms, vec!["*-linux", "x86_64-freebsd"]);
+
+    let results = ranked_data(&index, &["tool"]);
+    let oldtool = results.iter().find(|x| x.pname == "oldtool").unwrap();
+    assert!(oldtool.broken);
+    assert_eq!(
+        results
+            .iter()
+            .find(|x| x.pname == "macos-only")
+            .unwrap()
+            .unsupported,
+        !currentsystem().ends_with("darwin")
+    );
+}
+
+fn ranked_data(index: &Index, query: &[&str]) -> Vec<PkgData> {
+    index.rank(&query.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+}
+
+#[test]
+fn suggest_similar_attributes() {
+    let index = index();
+    assert_eq!(index.suggest("helo", 3), vec!["hello"]);
+    assert_eq!(index.suggest("Hello", 3), vec!["hello"]);
+    assert_eq!(
+        index.suggest("python3Packages.reqests", 3),
+        vec!["python3Packages.requests"]
+    );
+    // Nested attributes are found by their last part
+    assert_eq!(
+        index.suggest("requests", 3),
+        vec!["python3Packages.requests"]
+    );
+    assert_eq!(index.suggest("prettier", 3), vec!["nodePackages.prettier"]);
+    assert_eq!(index.suggest("gti", 3), vec!["git"]);
+    assert!(index.suggest("nosuchpackage", 3).is_empty());
+}
@@ -0,0 +1,31 @@
+use npkg::*;
+
+#[test]
+fn select_numbers_and_ranges() {
+    assert_eq!(selection("1 3 5-7", 8), Ok(vec![0, 2, 4, 5, 6]));
+    assert_eq!(selection("3,1, 1", 3), Ok(vec![0, 2]));
+    assert_eq!(selection("3-1", 3), Ok(vec![0, 1, 2]));
+    assert_eq!(selection("  \n", 3), Ok(vec![]));
+}
+
+#[test]
+fn select_exclusions() {
+    assert_eq!(selection("1-5 ^2 ^4", 5), Ok(vec![0, 2, 4]));
+    assert_eq!(selection("^1", 3), Ok(vec![1, 2]));
+    assert_eq!(selection("^2-3", 4), Ok(vec![0, 3]));
+    assert_eq!(selection("^1-3", 3), Ok(vec![]));
+}
+
+#[test]
+fn select_invalid() {
+    assert_eq!(
+        selection("1 a", 3),
+        Err(SelectionError::Invalid("a".to_string()))
+    );
+    assert_eq!(
+        selection("2-", 3),
+        Err(SelectionError::Invalid("2-".to_string()))
+    );
+    assert_eq!(selection("0", 3), Err(SelectionError::OutOfRange(0, 3)));
+    assert_eq!(selection("2-9", 3), Err(SelectionError::OutOfRange(9, 3)));
+}